@@ -0,0 +1,20 @@
+-- users mirrored from Keycloak on sign up, and the contacts each of them keeps
+CREATE TABLE IF NOT EXISTS users (
+    user_id UUID PRIMARY KEY,
+    first_name TEXT,
+    last_name TEXT,
+    email TEXT,
+    email_verified BOOLEAN NOT NULL DEFAULT false
+);
+
+CREATE TABLE IF NOT EXISTS contacts (
+    id SERIAL PRIMARY KEY,
+    user_id UUID NOT NULL,
+    friend_id UUID NOT NULL,
+    name TEXT NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    UNIQUE (user_id, friend_id)
+);
+
+CREATE INDEX IF NOT EXISTS contacts_friend_id_idx
+    ON contacts (friend_id);
@@ -0,0 +1,15 @@
+CREATE TABLE IF NOT EXISTS messages (
+    id UUID PRIMARY KEY,
+    conversation_id UUID NOT NULL,
+    author_id UUID NOT NULL,
+    author_username TEXT NOT NULL,
+    author_email TEXT NOT NULL,
+    author_avatar TEXT NOT NULL,
+    content TEXT NOT NULL,
+    content_type TEXT NOT NULL DEFAULT 'text',
+    status TEXT NOT NULL DEFAULT 'sent',
+    created_at TIMESTAMP NOT NULL DEFAULT now()
+);
+
+CREATE INDEX IF NOT EXISTS messages_conversation_id_created_at_idx
+    ON messages (conversation_id, created_at, id);
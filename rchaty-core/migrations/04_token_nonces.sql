@@ -0,0 +1,9 @@
+-- nonces of single-use links (e.g. email verification) that were already used,
+-- rows can go once the link they belong to has expired anyway
+CREATE TABLE IF NOT EXISTS consumed_token_nonces (
+    nonce TEXT PRIMARY KEY,
+    expires_at TIMESTAMP NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS consumed_token_nonces_expires_at_idx
+    ON consumed_token_nonces (expires_at);
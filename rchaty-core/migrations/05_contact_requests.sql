@@ -0,0 +1,13 @@
+-- pending contact requests, a row goes away once the request is accepted,
+-- declined or cancelled
+CREATE TABLE IF NOT EXISTS contact_requests (
+    id UUID PRIMARY KEY,
+    sender_id UUID NOT NULL,
+    recipient_id UUID NOT NULL,
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    UNIQUE (sender_id, recipient_id),
+    CHECK (sender_id <> recipient_id)
+);
+
+CREATE INDEX IF NOT EXISTS contact_requests_recipient_id_idx
+    ON contact_requests (recipient_id);
@@ -0,0 +1,12 @@
+-- users are searched by prefix of their username, names or email, case-insensitively
+ALTER TABLE users ADD COLUMN IF NOT EXISTS username TEXT;
+
+CREATE INDEX IF NOT EXISTS users_username_prefix_idx
+    ON users (lower(username) text_pattern_ops);
+CREATE INDEX IF NOT EXISTS users_first_name_prefix_idx
+    ON users (lower(first_name) text_pattern_ops);
+CREATE INDEX IF NOT EXISTS users_last_name_prefix_idx
+    ON users (lower(last_name) text_pattern_ops);
+-- also serves the exact lower(email) lookup of contact requests by email
+CREATE INDEX IF NOT EXISTS users_email_prefix_idx
+    ON users (lower(email) text_pattern_ops);
@@ -0,0 +1,13 @@
+-- users a user blocked or muted, one row per pair holding whichever was set last
+CREATE TABLE IF NOT EXISTS user_blocks (
+    user_id UUID NOT NULL,
+    blocked_id UUID NOT NULL,
+    kind TEXT NOT NULL CHECK (kind IN ('block', 'mute')),
+    created_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, blocked_id),
+    CHECK (user_id <> blocked_id)
+);
+
+-- looked up by author when routing messages
+CREATE INDEX IF NOT EXISTS user_blocks_blocked_id_idx
+    ON user_blocks (blocked_id);
@@ -0,0 +1,3 @@
+-- unread counts of the contact list only look at messages not read yet
+CREATE INDEX IF NOT EXISTS messages_unread_idx
+    ON messages (conversation_id, author_id) WHERE status <> 'read';
@@ -0,0 +1,12 @@
+-- open sockets per user on each server node, a node refreshes heartbeat_at of its
+-- rows while it runs so the rows of a node that went away stop counting
+CREATE TABLE IF NOT EXISTS presence_sessions (
+    node_id UUID NOT NULL,
+    user_id UUID NOT NULL,
+    sessions INTEGER NOT NULL DEFAULT 0,
+    heartbeat_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (user_id, node_id)
+);
+
+CREATE INDEX IF NOT EXISTS presence_sessions_node_id_idx
+    ON presence_sessions (node_id);
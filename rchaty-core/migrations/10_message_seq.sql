@@ -0,0 +1,25 @@
+-- insertion order of messages, the tiebreaker for messages sent at the same time
+CREATE SEQUENCE IF NOT EXISTS messages_seq_seq;
+
+DO $$
+BEGIN
+    IF NOT EXISTS (SELECT 1 FROM information_schema.columns
+                   WHERE table_name = 'messages' AND column_name = 'seq') THEN
+        ALTER TABLE messages ADD COLUMN seq BIGINT;
+        -- messages already stored keep the order they were listed in so far
+        UPDATE messages SET seq = ordered.seq
+        FROM (SELECT id, row_number() OVER (ORDER BY created_at, id) AS seq FROM messages) ordered
+        WHERE messages.id = ordered.id;
+        PERFORM setval('messages_seq_seq', COALESCE((SELECT max(seq) FROM messages), 0) + 1, false);
+        ALTER TABLE messages
+            ALTER COLUMN seq SET DEFAULT nextval('messages_seq_seq'),
+            ALTER COLUMN seq SET NOT NULL;
+        ALTER SEQUENCE messages_seq_seq OWNED BY messages.seq;
+    END IF;
+END
+$$;
+
+CREATE UNIQUE INDEX IF NOT EXISTS messages_conversation_id_seq_idx
+    ON messages (conversation_id, seq);
+
+DROP INDEX IF EXISTS messages_conversation_id_created_at_idx;
@@ -0,0 +1,21 @@
+-- delivered/read state of a message for each recipient, `messages.status` only
+-- summarizes them for the author
+CREATE TABLE IF NOT EXISTS message_receipts (
+    message_id UUID NOT NULL REFERENCES messages (id) ON DELETE CASCADE,
+    user_id UUID NOT NULL,
+    status TEXT NOT NULL CHECK (status IN ('delivered', 'read')),
+    updated_at TIMESTAMP NOT NULL DEFAULT now(),
+    PRIMARY KEY (message_id, user_id)
+);
+
+-- messages acknowledged before receipts were tracked count as acknowledged by every recipient
+INSERT INTO message_receipts (message_id, user_id, status)
+SELECT m.id, mb.user_id, m.status FROM messages m
+JOIN conversation_members mb ON mb.conversation_id = m.conversation_id AND mb.user_id <> m.author_id
+WHERE m.status IN ('delivered', 'read')
+ON CONFLICT (message_id, user_id) DO NOTHING;
+
+-- unread counts now look at receipts, no longer at the status of the message
+DROP INDEX IF EXISTS messages_unread_idx;
+CREATE INDEX IF NOT EXISTS messages_conversation_id_author_id_idx
+    ON messages (conversation_id, author_id);
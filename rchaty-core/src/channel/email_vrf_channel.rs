@@ -20,6 +20,12 @@ pub trait EmailVerifiedChannel {
     ) -> Result<usize, broadcast::error::SendError<EmailVerifiedMessage>>;
 }
 
+impl Default for EmailVerifiedChannelImpl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EmailVerifiedChannelImpl {
     pub fn new() -> Self {
         let (tx, _rx) = broadcast::channel::<EmailVerifiedMessage>(100);
@@ -1,2 +1,5 @@
 pub mod master;
 pub mod model;
+pub mod pg_master;
+pub mod presence;
+pub mod typing;
@@ -1,91 +1,498 @@
-use super::model::MessageData;
+use super::{
+    model::{ContactRequest, MessageData, Restriction, TypingData},
+    presence::PresenceData,
+};
+use crate::BaseError;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::HashMap,
-    fmt::Debug,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{
+    sync::broadcast::{self, Receiver, Sender},
+    task::JoinHandle,
 };
-use tokio::sync::broadcast::{self, Sender};
+
+/// How long a channel without subscribers is kept around before it is dropped.
+pub const CHANNEL_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often idle channels are looked for.
+pub const CHANNEL_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
 
 #[derive(Clone)]
 pub struct MasterChannelImpl {
-    tx: Arc<Mutex<HashMap<String, Sender<InnerNodeChannelData>>>>,
+    tx: Arc<Mutex<HashMap<String, UserChannel>>>,
+    idle_timeout: Duration,
+    removed: Arc<AtomicU64>,
+}
+
+/// The live subscribers of a channel are the receivers of its broadcast sender,
+/// so dropping a socket's receiver is all it takes to release the channel.
+struct UserChannel {
+    tx: Sender<Delivery>,
+    idle_since: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ChannelMetrics {
+    /// Channels currently held, idle ones included.
+    pub channels: usize,
+    /// Channels with at least one subscriber.
+    pub active: usize,
+    pub subscribers: usize,
+    /// Idle channels dropped since start.
+    pub removed: u64,
 }
 
-// type RootChannelTx = Arc<Mutex<HashMap<String, Sender<Box<dyn ChannelData>>>>>;
-type InnerNodeChannelData = Arc<dyn ChannelData + Send + Sync>;
+impl Default for MasterChannelImpl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl MasterChannelImpl {
     pub fn new() -> Self {
+        Self::with_idle_timeout(CHANNEL_IDLE_TIMEOUT)
+    }
+
+    pub fn with_idle_timeout(idle_timeout: Duration) -> Self {
         let channels = Arc::new(Mutex::new(HashMap::new()));
-        Self { tx: channels }
+        Self {
+            tx: channels,
+            idle_timeout,
+            removed: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    pub fn has_channel(&self, user_id: &str) -> bool {
+        self.tx.lock().unwrap().contains_key(user_id)
+    }
+
+    /// Drops the channels that went without subscribers for longer than the idle timeout.
+    pub fn sweep(&self) -> usize {
+        self.sweep_at(Instant::now())
+    }
+
+    fn sweep_at(&self, now: Instant) -> usize {
+        let mut tx = self.tx.lock().unwrap();
+        let before = tx.len();
+        tx.retain(|_, channel| {
+            if channel.tx.receiver_count() > 0 {
+                channel.idle_since = None;
+                return true;
+            }
+            let idle_since = *channel.idle_since.get_or_insert(now);
+            now.duration_since(idle_since) < self.idle_timeout
+        });
+        let removed = before - tx.len();
+        self.removed.fetch_add(removed as u64, Ordering::Relaxed);
+        removed
+    }
+
+    /// Sweeps idle channels in the background for as long as the server runs.
+    pub fn spawn_gc(&self) -> JoinHandle<()> {
+        let channel = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(CHANNEL_SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                let removed = channel.sweep();
+                let metrics = channel.metrics();
+                tracing::debug!(
+                    "channels: {}, active: {}, subscribers: {}, removed: {} (total {})",
+                    metrics.channels,
+                    metrics.active,
+                    metrics.subscribers,
+                    removed,
+                    metrics.removed
+                );
+            }
+        })
     }
 }
 
 #[async_trait::async_trait]
 pub trait MasterChannel {
-    fn create_channel(&self, user_id: &str);
-    async fn tx(&self, user_id: &str) -> Option<Sender<Arc<dyn ChannelData + Send + Sync>>>;
+    /// Subscribes a socket to the user's channel, creating the channel if needed.
+    fn subscribe(&self, user_id: &str) -> Receiver<Delivery>;
+    async fn tx(&self, user_id: &str) -> Option<Sender<Delivery>>;
+    /// Delivers to every socket of the user, on whichever node it is connected.
+    async fn send(&self, user_id: &str, delivery: Delivery) -> Result<(), BaseError>;
+    /// Ends every socket subscribed to the user's channel, on whichever node it is connected.
+    async fn close(&self, user_id: &str) -> Result<(), BaseError>;
+    fn metrics(&self) -> ChannelMetrics;
+
+    /// Delivers the same data to each of `user_ids`, channels relaying between
+    /// nodes override it to publish once for all of them.
+    async fn send_many(&self, user_ids: &[String], delivery: Delivery) {
+        for user_id in user_ids {
+            if let Err(e) = self.send(user_id, delivery.clone()).await {
+                tracing::warn!("user: {} failed to receive data: {}", user_id, e);
+            }
+        }
+    }
+
+    /// Delivers `event` to each of `user_ids` as `send` does, leaving out whoever
+    /// blocks its author and muting it for whoever mutes them, see `Restriction`.
+    async fn send_restricted(
+        &self,
+        user_ids: &[String],
+        event: ChannelEvent,
+        restrictions: &HashMap<String, Restriction>,
+    ) {
+        let mut unmuted = Vec::new();
+        let mut muted = Vec::new();
+        for user_id in user_ids {
+            match restrictions.get(user_id) {
+                Some(Restriction::Block) => {}
+                Some(Restriction::Mute) => muted.push(user_id.clone()),
+                None => unmuted.push(user_id.clone()),
+            }
+        }
+        let event = Arc::new(event);
+        for (user_ids, muted) in [(unmuted, false), (muted, true)] {
+            if user_ids.is_empty() {
+                continue;
+            }
+            let delivery = Delivery {
+                event: event.clone(),
+                muted,
+            };
+            self.send_many(&user_ids, delivery).await;
+        }
+    }
 }
 
 #[async_trait::async_trait]
 impl MasterChannel for MasterChannelImpl {
-    fn create_channel(&self, user_id: &str) {
+    fn subscribe(&self, user_id: &str) -> Receiver<Delivery> {
+        // subscribing under the lock so a sweep never drops a channel about to be used
         let mut tx = self.tx.lock().unwrap();
-        match tx.get(user_id) {
-            Some(_) => {
-                tracing::info!("channel already exists for user_id: {}", user_id);
-            }
-            None => {
-                let (node_tx, _) = broadcast::channel::<InnerNodeChannelData>(100);
-                tx.insert(user_id.to_string(), node_tx);
+        let channel = tx.entry(user_id.to_string()).or_insert_with(|| {
+            let (node_tx, _) = broadcast::channel::<Delivery>(100);
+            UserChannel {
+                tx: node_tx,
+                idle_since: None,
             }
-        }
+        });
+        channel.idle_since = None;
+        channel.tx.subscribe()
     }
 
-    async fn tx(&self, user_id: &str) -> Option<Sender<Arc<dyn ChannelData + Send + Sync>>> {
+    async fn tx(&self, user_id: &str) -> Option<Sender<Delivery>> {
         let tx = self.tx.lock().unwrap();
         match tx.get(user_id) {
-            Some(tx) => Some(tx.clone()),
+            Some(channel) => Some(channel.tx.clone()),
             None => {
                 tracing::info!("channel does not exist for user_id: {}", user_id);
                 None
             }
         }
     }
-}
 
-pub trait ChannelData: Send + Sync + Debug {
-    fn data(&self) -> String;
-    fn content(&self) -> String;
-    fn created_at(&self) -> String;
+    async fn send(&self, user_id: &str, delivery: Delivery) -> Result<(), BaseError> {
+        let tx = self
+            .tx
+            .lock()
+            .unwrap()
+            .get(user_id)
+            .map(|channel| channel.tx.clone());
+        if let Some(tx) = tx {
+            if let Err(e) = tx.send(delivery) {
+                tracing::debug!("no active receiver for user_id: {}, err: {}", user_id, e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn close(&self, user_id: &str) -> Result<(), BaseError> {
+        // dropping the sender makes every receiver see `RecvError::Closed`
+        if self.tx.lock().unwrap().remove(user_id).is_some() {
+            tracing::info!("closed channel for user_id: {}", user_id);
+        }
+        Ok(())
+    }
+
+    fn metrics(&self) -> ChannelMetrics {
+        let tx = self.tx.lock().unwrap();
+        let mut metrics = ChannelMetrics {
+            channels: tx.len(),
+            removed: self.removed.load(Ordering::Relaxed),
+            ..Default::default()
+        };
+        for channel in tx.values() {
+            let subscribers = channel.tx.receiver_count();
+            if subscribers > 0 {
+                metrics.active += 1;
+            }
+            metrics.subscribers += subscribers;
+        }
+        metrics
+    }
 }
 
+/// An event pushed to a user's sockets, tagged by its `channel_type` when relayed.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct ChannelDataImpl<'a> {
-    pub channel_type: &'a str,
-    pub data: MessageData,
+#[serde(tag = "channel_type", content = "data", rename_all = "camelCase")]
+pub enum ChannelEvent {
+    /// A new chat message.
+    ChatMessage(MessageData),
+    /// A delivery/read receipt sent back to the author.
+    MessageStatus(MessageData),
+    /// An ephemeral typing indicator, never persisted.
+    Typing(TypingData),
+    /// An online/offline transition of one of the user's contacts.
+    Presence(PresenceData),
+    /// A new contact request, sent to the recipient.
+    ContactRequest(ContactRequest),
+    /// Sent to the sender once the recipient accepted.
+    ContactRequestAccepted(ContactRequest),
+    /// Sent to the recipient when the sender took the request back.
+    ContactRequestCancelled(ContactRequest),
 }
 
-impl<'a> ChannelDataImpl<'a> {
-    pub fn new_chat_msg(data: MessageData) -> ChannelDataImpl<'a> {
-        Self {
-            channel_type: "chatMessage",
-            data,
+impl ChannelEvent {
+    /// The serde tag of the event.
+    pub fn channel_type(&self) -> &'static str {
+        match self {
+            ChannelEvent::ChatMessage(_) => "chatMessage",
+            ChannelEvent::MessageStatus(_) => "messageStatus",
+            ChannelEvent::Typing(_) => "typing",
+            ChannelEvent::Presence(_) => "presence",
+            ChannelEvent::ContactRequest(_) => "contactRequest",
+            ChannelEvent::ContactRequestAccepted(_) => "contactRequestAccepted",
+            ChannelEvent::ContactRequestCancelled(_) => "contactRequestCancelled",
+        }
+    }
+
+    /// Rebuilds an event about a stored message from its `channel_type`, `None`
+    /// when that kind of event is not about a message.
+    pub fn from_message(channel_type: &str, message: MessageData) -> Option<Self> {
+        let event = serde_json::json!({ "channel_type": channel_type, "data": message });
+        serde_json::from_value::<ChannelEvent>(event)
+            .ok()
+            .filter(|event| event.message().is_some())
+    }
+
+    /// The user who caused the event.
+    pub fn author_id(&self) -> &str {
+        match self {
+            ChannelEvent::ChatMessage(message) | ChannelEvent::MessageStatus(message) => {
+                &message.author.id
+            }
+            ChannelEvent::Typing(typing) => &typing.user_id,
+            ChannelEvent::Presence(presence) => &presence.user_id,
+            ChannelEvent::ContactRequest(request)
+            | ChannelEvent::ContactRequestCancelled(request) => &request.sender_id,
+            ChannelEvent::ContactRequestAccepted(request) => &request.recipient_id,
+        }
+    }
+
+    /// The stored message the event is about, it can be read back by this id.
+    pub fn message(&self) -> Option<&MessageData> {
+        match self {
+            ChannelEvent::ChatMessage(message) | ChannelEvent::MessageStatus(message) => {
+                Some(message)
+            }
+            _ => None,
         }
     }
 }
 
-impl<'a> ChannelData for ChannelDataImpl<'a> {
-    fn data(&self) -> String {
-        serde_json::to_string(self).unwrap()
+/// An event as delivered to one user, who may have muted its author.
+#[derive(Debug, Clone)]
+pub struct Delivery {
+    pub event: Arc<ChannelEvent>,
+    /// Still delivered but without notifying, the recipient muted the author.
+    pub muted: bool,
+}
+
+impl From<ChannelEvent> for Delivery {
+    fn from(event: ChannelEvent) -> Self {
+        Delivery {
+            event: Arc::new(event),
+            muted: false,
+        }
     }
+}
+
+#[cfg(test)]
+mod tests {
 
-    fn content(&self) -> String {
-        self.data.content.clone()
+    use super::*;
+    use crate::chatchannel::model::{Author, ContentType, MessageStatus};
+
+    #[test]
+    fn test_subscribers_are_counted() {
+        let master = MasterChannelImpl::new();
+        let rx_a = master.subscribe("user-a");
+        let rx_b = master.subscribe("user-a");
+        let _rx_c = master.subscribe("user-b");
+
+        let metrics = master.metrics();
+        assert_eq!(metrics.channels, 2);
+        assert_eq!(metrics.active, 2);
+        assert_eq!(metrics.subscribers, 3);
+
+        drop(rx_a);
+        drop(rx_b);
+        let metrics = master.metrics();
+        assert_eq!(metrics.active, 1);
+        assert_eq!(metrics.subscribers, 1);
     }
 
-    fn created_at(&self) -> String {
-        self.data.created_at.clone()
+    #[test]
+    fn test_idle_channel_is_removed_after_timeout() {
+        let master = MasterChannelImpl::with_idle_timeout(Duration::from_secs(60));
+        let rx = master.subscribe("user-a");
+        let now = Instant::now();
+        assert_eq!(master.sweep_at(now), 0);
+
+        drop(rx);
+        // first sweep only notices the channel went idle
+        assert_eq!(master.sweep_at(now), 0);
+        assert_eq!(master.sweep_at(now + Duration::from_secs(59)), 0);
+        assert!(master.has_channel("user-a"));
+
+        assert_eq!(master.sweep_at(now + Duration::from_secs(60)), 1);
+        assert!(!master.has_channel("user-a"));
+        assert_eq!(master.metrics().removed, 1);
+    }
+
+    #[test]
+    fn test_reconnect_keeps_channel() {
+        let master = MasterChannelImpl::with_idle_timeout(Duration::from_secs(60));
+        let now = Instant::now();
+        drop(master.subscribe("user-a"));
+        master.sweep_at(now);
+
+        // reconnecting before the timeout resets the idle clock
+        let _rx = master.subscribe("user-a");
+        assert_eq!(master.sweep_at(now + Duration::from_secs(120)), 0);
+        assert!(master.has_channel("user-a"));
+    }
+
+    #[test]
+    fn test_connect_disconnect_churn() {
+        let master = MasterChannelImpl::with_idle_timeout(Duration::from_secs(60));
+        let now = Instant::now();
+        for round in 0..100 {
+            let receivers: Vec<_> = (0..10)
+                .map(|i| master.subscribe(&format!("user-{}-{}", round, i)))
+                .collect();
+            assert_eq!(master.metrics().active, 10);
+            drop(receivers);
+            master.sweep_at(now);
+        }
+        assert_eq!(master.metrics().channels, 1000);
+        assert_eq!(master.metrics().active, 0);
+
+        assert_eq!(master.sweep_at(now + Duration::from_secs(60)), 1000);
+        let metrics = master.metrics();
+        assert_eq!(metrics.channels, 0);
+        assert_eq!(metrics.removed, 1000);
+    }
+
+    fn chat_msg(content: &str) -> ChannelEvent {
+        let author = Author::new(
+            "user-b".to_string(),
+            "b".to_string(),
+            "b@example.com".to_string(),
+            String::new(),
+        );
+        let data = MessageData::new(
+            "message-id".to_string(),
+            "conversation-id".to_string(),
+            author,
+            content.to_string(),
+            ContentType::Text,
+            String::new(),
+            MessageStatus::Sent,
+        );
+        ChannelEvent::ChatMessage(data)
+    }
+
+    fn content(delivery: &Delivery) -> &str {
+        match delivery.event.as_ref() {
+            ChannelEvent::ChatMessage(message) => &message.content,
+            _ => "",
+        }
+    }
+
+    #[test]
+    fn test_channel_type_is_the_serde_tag() {
+        let event = chat_msg("hello");
+        let json = serde_json::to_value(&event).unwrap();
+        assert_eq!(json["channel_type"], event.channel_type());
+
+        let message = event.message().unwrap().clone();
+        let status = ChannelEvent::from_message("messageStatus", message.clone()).unwrap();
+        assert_eq!(status.channel_type(), "messageStatus");
+        assert!(ChannelEvent::from_message("presence", message).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_reaches_every_subscriber() {
+        let master = MasterChannelImpl::new();
+        let mut rx_a = master.subscribe("user-a");
+        let mut rx_b = master.subscribe("user-a");
+        master
+            .send("user-a", chat_msg("hello").into())
+            .await
+            .unwrap();
+        assert_eq!(content(&rx_a.recv().await.unwrap()), "hello");
+        assert_eq!(content(&rx_b.recv().await.unwrap()), "hello");
+
+        // sending to a user without a channel does not create one
+        master
+            .send("user-c", chat_msg("hello").into())
+            .await
+            .unwrap();
+        assert!(!master.has_channel("user-c"));
+    }
+
+    #[tokio::test]
+    async fn test_send_restricted_skips_blockers_and_mutes() {
+        let master = MasterChannelImpl::new();
+        let mut rx_a = master.subscribe("user-a");
+        let mut rx_c = master.subscribe("user-c");
+        let mut rx_d = master.subscribe("user-d");
+        let restrictions = HashMap::from([
+            ("user-c".to_string(), Restriction::Block),
+            ("user-d".to_string(), Restriction::Mute),
+        ]);
+        let user_ids = ["user-a", "user-c", "user-d"].map(String::from);
+        master
+            .send_restricted(&user_ids, chat_msg("hello"), &restrictions)
+            .await;
+
+        assert!(!rx_a.recv().await.unwrap().muted);
+        assert!(rx_c.try_recv().is_err());
+        let muted = rx_d.recv().await.unwrap();
+        assert!(muted.muted);
+        assert_eq!(content(&muted), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_close_ends_subscribers() {
+        let master = MasterChannelImpl::new();
+        let mut rx_a = master.subscribe("user-a");
+        let mut rx_b = master.subscribe("user-b");
+        master.close("user-a").await.unwrap();
+        assert!(matches!(
+            rx_a.recv().await,
+            Err(broadcast::error::RecvError::Closed)
+        ));
+        assert!(!master.has_channel("user-a"));
+
+        // other users keep their channel
+        master
+            .send("user-b", chat_msg("hello").into())
+            .await
+            .unwrap();
+        assert_eq!(content(&rx_b.recv().await.unwrap()), "hello");
     }
 }
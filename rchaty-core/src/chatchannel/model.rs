@@ -1,7 +1,12 @@
+use std::fmt::Display;
+
+use chrono::{NaiveDateTime, SubsecRound, Utc};
 use serde::{Deserialize, Serialize};
+use uuid::Uuid;
 
-#[derive(Debug, Clone, Deserialize)]
-#[serde(untagged)]
+use crate::BaseError;
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum MessageStatus {
     Sent,
     Delivered,
@@ -17,15 +22,27 @@ impl Serialize for MessageStatus {
     }
 }
 
-impl MessageStatus {
-    pub fn to_string(&self) -> String {
+impl<'de> Deserialize<'de> for MessageStatus {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let status = String::deserialize(deserializer)?;
+        Ok(MessageStatus::from_string(&status))
+    }
+}
+
+impl Display for MessageStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            MessageStatus::Sent => "sent".to_string(),
-            MessageStatus::Delivered => "delivered".to_string(),
-            MessageStatus::Read => "read".to_string(),
+            MessageStatus::Sent => write!(f, "sent"),
+            MessageStatus::Delivered => write!(f, "delivered"),
+            MessageStatus::Read => write!(f, "read"),
         }
     }
+}
 
+impl MessageStatus {
     pub fn from_string(status: &str) -> Self {
         match status {
             "sent" => MessageStatus::Sent,
@@ -36,6 +53,15 @@ impl MessageStatus {
     }
 }
 
+/// Format used for `MessageData::created_at`, keeping the microseconds a TIMESTAMP
+/// column stores so messages sent within the same second stay in order.
+pub const MESSAGE_DATE_FORMAT: &str = "%Y-%m-%d %H:%M:%S%.f";
+
+/// Now, at the precision of a TIMESTAMP column.
+pub fn now_timestamp() -> NaiveDateTime {
+    Utc::now().naive_utc().trunc_subsecs(6)
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct MessageData {
     pub id: String,
@@ -69,12 +95,123 @@ impl MessageData {
     }
 }
 
+/// Maximum number of characters accepted for a single chat message.
+pub const MAX_MESSAGE_LENGTH: usize = 4000;
+
+/// Frame sent by a client over the chat socket.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum IncomingFrame {
+    ChatMessage(IncomingMessage),
+    Read { message_id: String },
+    Typing { conversation_id: String },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct IncomingMessage {
+    pub conversation_id: String,
+    pub content: String,
+    pub content_type: Option<String>,
+}
+
+impl IncomingMessage {
+    pub fn validate(&self) -> Result<(), BaseError> {
+        Uuid::parse_str(&self.conversation_id)
+            .map_err(|_| BaseError::new(400, "invalid conversation"))?;
+
+        let content = self.content.trim();
+        if content.is_empty() {
+            return Err(BaseError::new(400, "message is empty"));
+        }
+        if content.chars().count() > MAX_MESSAGE_LENGTH {
+            return Err(BaseError::new(400, "message is too long"));
+        }
+        Ok(())
+    }
+
+    /// Builds the message to be stored, ids and timestamps are left for the server to assign.
+    pub fn into_message_data(self, author: Author) -> MessageData {
+        let content_type = self
+            .content_type
+            .as_deref()
+            .map(ContentType::from_string)
+            .unwrap_or(ContentType::Text);
+        MessageData::new(
+            String::new(),
+            self.conversation_id,
+            author,
+            self.content.trim().to_string(),
+            content_type,
+            String::new(),
+            MessageStatus::Sent,
+        )
+    }
+}
+
+/// Conversation id shared by two users, the same regardless of who starts it.
+pub fn direct_conversation_id(user_a: &str, user_b: &str) -> String {
+    let (first, second) = if user_a <= user_b {
+        (user_a, user_b)
+    } else {
+        (user_b, user_a)
+    };
+    let name = format!("{}:{}", first, second);
+    Uuid::new_v5(&Uuid::NAMESPACE_OID, name.as_bytes()).to_string()
+}
+
+/// What a user set on another one. Messages of a blocked user are never
+/// delivered and both users stop finding each other, a muted user's messages
+/// are delivered without notifying.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Restriction {
+    Block,
+    Mute,
+}
+
+impl Display for Restriction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Restriction::Block => write!(f, "block"),
+            Restriction::Mute => write!(f, "mute"),
+        }
+    }
+}
+
+impl Restriction {
+    pub fn parse(kind: &str) -> Result<Self, BaseError> {
+        match kind {
+            "block" => Ok(Restriction::Block),
+            "mute" => Ok(Restriction::Mute),
+            _ => Err(BaseError::new(400, "unknown restriction")),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ContactRequest {
+    pub id: String,
+    pub sender_id: String,
+    pub sender_name: String,
+    pub recipient_id: String,
+    pub recipient_name: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// Someone started or stopped typing in a conversation.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct TypingData {
+    pub conversation_id: String,
+    pub user_id: String,
+    pub username: String,
+    pub typing: bool,
+}
+
 #[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct Author {
-    id: String,
-    username: String,
-    email: String,
-    avatar: String,
+    pub id: String,
+    pub username: String,
+    pub email: String,
+    pub avatar: String,
 }
 
 impl Author {
@@ -88,8 +225,7 @@ impl Author {
     }
 }
 
-#[derive(Debug, Clone, Deserialize)]
-#[serde(untagged)]
+#[derive(Debug, Clone)]
 pub enum ContentType {
     Text,
     Image,
@@ -104,14 +240,26 @@ impl Serialize for ContentType {
     }
 }
 
-impl ContentType {
-    pub fn to_string(&self) -> String {
+impl<'de> Deserialize<'de> for ContentType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let content_type = String::deserialize(deserializer)?;
+        Ok(ContentType::from_string(&content_type))
+    }
+}
+
+impl Display for ContentType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            ContentType::Text => "text".to_string(),
-            ContentType::Image => "image".to_string(),
+            ContentType::Text => write!(f, "text"),
+            ContentType::Image => write!(f, "image"),
         }
     }
+}
 
+impl ContentType {
     pub fn from_string(content_type: &str) -> Self {
         match content_type {
             "text" => ContentType::Text,
@@ -120,3 +268,22 @@ impl ContentType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_message_date_keeps_microseconds() {
+        let created_at =
+            NaiveDateTime::parse_from_str("2024-05-01 10:00:00.123456", "%Y-%m-%d %H:%M:%S%.f")
+                .unwrap();
+        let formatted = created_at.format(MESSAGE_DATE_FORMAT).to_string();
+        assert_eq!(formatted, "2024-05-01 10:00:00.123456");
+        let parsed = NaiveDateTime::parse_from_str(&formatted, MESSAGE_DATE_FORMAT).unwrap();
+        assert_eq!(parsed, created_at);
+        // dates saved to the second still parse
+        assert!(NaiveDateTime::parse_from_str("2024-05-01 10:00:00", MESSAGE_DATE_FORMAT).is_ok());
+    }
+}
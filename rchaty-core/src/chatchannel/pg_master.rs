@@ -0,0 +1,309 @@
+use std::{sync::Arc, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{
+    broadcast::{Receiver, Sender},
+    mpsc, RwLock,
+};
+use tokio_postgres::{AsyncMessage, Client, NoTls};
+use uuid::Uuid;
+
+use super::master::{ChannelEvent, ChannelMetrics, Delivery, MasterChannel, MasterChannelImpl};
+use crate::{
+    db::repository::{DBConfig, DB},
+    BaseError,
+};
+
+/// Postgres channel every node listens on.
+const RELAY_CHANNEL: &str = "rchaty_master_channel";
+
+/// NOTIFY payloads must be shorter than 8000 bytes.
+const MAX_NOTIFY_PAYLOAD: usize = 7999;
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// `channel_type` of the envelope closing a user's channel on every node.
+const CLOSE_CHANNEL: &str = "closeChannel";
+
+/// What goes over NOTIFY, `data` is the serialized `ChannelEvent` for every one of `user_ids`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RelayEnvelope {
+    node_id: String,
+    user_ids: Vec<String>,
+    channel_type: String,
+    message_id: String,
+    /// Left out when too big for a notification, the message is read back by id instead.
+    data: Option<String>,
+    #[serde(default)]
+    muted: bool,
+}
+
+/// `MasterChannel` shared by several `rchaty-server` nodes.
+///
+/// Sockets still subscribe to the in-process channels of `MasterChannelImpl`,
+/// every `send` is delivered locally right away and published with NOTIFY so
+/// the other nodes can deliver it to the sockets they hold.
+#[derive(Clone)]
+pub struct PgMasterChannelImpl {
+    node_id: String,
+    local: MasterChannelImpl,
+    config: Arc<DBConfig>,
+    db: Arc<dyn DB + Send + Sync>,
+    client: Arc<RwLock<Option<Client>>>,
+}
+
+impl PgMasterChannelImpl {
+    /// Starts listening in the background, reconnecting whenever the connection drops,
+    /// and sweeping the idle local channels.
+    pub fn connect(config: DBConfig, db: Arc<dyn DB + Send + Sync>) -> Self {
+        let channel = Self {
+            node_id: Uuid::new_v4().to_string(),
+            local: MasterChannelImpl::new(),
+            config: Arc::new(config),
+            db,
+            client: Arc::new(RwLock::new(None)),
+        };
+        channel.local.spawn_gc();
+        tokio::spawn(channel.clone().listen());
+        channel
+    }
+
+    async fn listen(self) {
+        loop {
+            match self.listen_once().await {
+                Ok(()) => tracing::warn!("relay connection closed, reconnecting"),
+                Err(e) => tracing::warn!("relay connection failed: {}, reconnecting", e),
+            }
+            *self.client.write().await = None;
+            tokio::time::sleep(RECONNECT_DELAY).await;
+        }
+    }
+
+    async fn listen_once(&self) -> Result<(), BaseError> {
+        let (client, mut connection) =
+            tokio_postgres::connect(&self.config.connection_string(), NoTls).await?;
+
+        // notifications only show up while polling the connection itself
+        let (notify_tx, mut notify_rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Some(message) = std::future::poll_fn(|cx| connection.poll_message(cx)).await {
+                match message {
+                    Ok(AsyncMessage::Notification(notification)) => {
+                        if notify_tx.send(notification).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(_) => {}
+                    Err(e) => {
+                        tracing::warn!("relay connection error: {}", e);
+                        break;
+                    }
+                }
+            }
+        });
+
+        client
+            .batch_execute(&format!("LISTEN {}", RELAY_CHANNEL))
+            .await?;
+        *self.client.write().await = Some(client);
+        tracing::info!("node: {} listening on {}", self.node_id, RELAY_CHANNEL);
+
+        while let Some(notification) = notify_rx.recv().await {
+            if let Err(e) = self.deliver(notification.payload()).await {
+                tracing::warn!("failed to deliver relayed data: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    async fn deliver(&self, payload: &str) -> Result<(), BaseError> {
+        let envelope: RelayEnvelope =
+            serde_json::from_str(payload).map_err(|e| BaseError::new(400, &e.to_string()))?;
+        // already delivered locally when it was sent
+        if envelope.node_id == self.node_id {
+            return Ok(());
+        }
+        // most nodes hold no socket for the users, skip decoding for them
+        let user_ids: Vec<&String> = envelope
+            .user_ids
+            .iter()
+            .filter(|user_id| self.local.has_channel(user_id))
+            .collect();
+        if user_ids.is_empty() {
+            return Ok(());
+        }
+        if envelope.channel_type == CLOSE_CHANNEL {
+            for user_id in user_ids {
+                self.local.close(user_id).await?;
+            }
+            return Ok(());
+        }
+
+        let delivery = Delivery {
+            event: Arc::new(self.decode(&envelope).await?),
+            muted: envelope.muted,
+        };
+        for user_id in user_ids {
+            self.local.send(user_id, delivery.clone()).await?;
+        }
+        Ok(())
+    }
+
+    async fn decode(&self, envelope: &RelayEnvelope) -> Result<ChannelEvent, BaseError> {
+        if let Some(data) = envelope.data.as_deref() {
+            return serde_json::from_str(data).map_err(|e| BaseError::new(400, &e.to_string()));
+        }
+        let message = self.db.get_message_by_id(&envelope.message_id).await?;
+        ChannelEvent::from_message(&envelope.channel_type, message)
+            .ok_or(BaseError::new(400, "unknown channel type"))
+    }
+
+    async fn publish(&self, user_ids: &[String], delivery: &Delivery) -> Result<(), BaseError> {
+        let event = delivery.event.as_ref();
+        let data = serde_json::to_string(event).map_err(|e| BaseError::new(500, &e.to_string()))?;
+        self.notify(&RelayEnvelope {
+            node_id: self.node_id.clone(),
+            user_ids: user_ids.to_vec(),
+            channel_type: event.channel_type().to_string(),
+            message_id: event
+                .message()
+                .map(|message| message.id.clone())
+                .unwrap_or_default(),
+            data: Some(data),
+            muted: delivery.muted,
+        })
+        .await
+    }
+
+    async fn notify(&self, envelope: &RelayEnvelope) -> Result<(), BaseError> {
+        let payloads = encode(envelope)?;
+
+        let client = self.client.read().await;
+        let client = client
+            .as_ref()
+            .ok_or(BaseError::new(503, "relay is not connected"))?;
+        for payload in payloads {
+            client
+                .execute("SELECT pg_notify($1, $2)", &[&RELAY_CHANNEL, &payload])
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Serializes `envelope` into as few NOTIFY payloads as fit, leaving out the data of
+/// a stored message first, it is read back by id, then splitting `user_ids`.
+fn encode(envelope: &RelayEnvelope) -> Result<Vec<String>, BaseError> {
+    let payload =
+        serde_json::to_string(envelope).map_err(|e| BaseError::new(500, &e.to_string()))?;
+    if payload.len() <= MAX_NOTIFY_PAYLOAD {
+        return Ok(vec![payload]);
+    }
+    if envelope.data.is_some() && !envelope.message_id.is_empty() {
+        return encode(&RelayEnvelope {
+            data: None,
+            ..envelope.clone()
+        });
+    }
+    if envelope.user_ids.len() < 2 {
+        return Err(BaseError::new(400, "data is too big to relay"));
+    }
+    let (head, tail) = envelope.user_ids.split_at(envelope.user_ids.len() / 2);
+    let mut payloads = encode(&RelayEnvelope {
+        user_ids: head.to_vec(),
+        ..envelope.clone()
+    })?;
+    payloads.extend(encode(&RelayEnvelope {
+        user_ids: tail.to_vec(),
+        ..envelope.clone()
+    })?);
+    Ok(payloads)
+}
+
+#[async_trait::async_trait]
+impl MasterChannel for PgMasterChannelImpl {
+    fn subscribe(&self, user_id: &str) -> Receiver<Delivery> {
+        self.local.subscribe(user_id)
+    }
+
+    async fn tx(&self, user_id: &str) -> Option<Sender<Delivery>> {
+        self.local.tx(user_id).await
+    }
+
+    async fn send(&self, user_id: &str, delivery: Delivery) -> Result<(), BaseError> {
+        self.local.send(user_id, delivery.clone()).await?;
+        self.publish(&[user_id.to_string()], &delivery).await
+    }
+
+    async fn send_many(&self, user_ids: &[String], delivery: Delivery) {
+        self.local.send_many(user_ids, delivery.clone()).await;
+        if let Err(e) = self.publish(user_ids, &delivery).await {
+            tracing::warn!(
+                "failed to relay {} to {} users: {}",
+                delivery.event.channel_type(),
+                user_ids.len(),
+                e
+            );
+        }
+    }
+
+    async fn close(&self, user_id: &str) -> Result<(), BaseError> {
+        self.local.close(user_id).await?;
+        self.notify(&RelayEnvelope {
+            node_id: self.node_id.clone(),
+            user_ids: vec![user_id.to_string()],
+            channel_type: CLOSE_CHANNEL.to_string(),
+            message_id: String::new(),
+            data: None,
+            muted: false,
+        })
+        .await
+    }
+
+    fn metrics(&self) -> ChannelMetrics {
+        self.local.metrics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn envelope(user_ids: usize, data: &str, message_id: &str) -> RelayEnvelope {
+        RelayEnvelope {
+            node_id: Uuid::new_v4().to_string(),
+            user_ids: (0..user_ids).map(|_| Uuid::new_v4().to_string()).collect(),
+            channel_type: "chatMessage".to_string(),
+            message_id: message_id.to_string(),
+            data: Some(data.to_string()),
+            muted: false,
+        }
+    }
+
+    #[test]
+    fn test_encode_measures_the_escaped_payload() {
+        // 6000 bytes of data, escaped to 12000 once in the envelope
+        let data = "\"".repeat(6000);
+        let payloads = encode(&envelope(1, &data, "message-id")).unwrap();
+        assert_eq!(payloads.len(), 1);
+        assert!(payloads[0].len() <= MAX_NOTIFY_PAYLOAD);
+        let relayed: RelayEnvelope = serde_json::from_str(&payloads[0]).unwrap();
+        assert!(relayed.data.is_none());
+
+        assert!(encode(&envelope(1, &data, "")).is_err());
+    }
+
+    #[test]
+    fn test_encode_splits_the_recipients() {
+        let envelope = envelope(500, "{}", "");
+        let payloads = encode(&envelope).unwrap();
+        assert!(payloads.len() > 1);
+        assert!(payloads.iter().all(|p| p.len() <= MAX_NOTIFY_PAYLOAD));
+        let user_ids: Vec<String> = payloads
+            .iter()
+            .flat_map(|p| serde_json::from_str::<RelayEnvelope>(p).unwrap().user_ids)
+            .collect();
+        assert_eq!(user_ids, envelope.user_ids);
+    }
+}
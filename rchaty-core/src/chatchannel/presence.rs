@@ -0,0 +1,144 @@
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use super::{
+    master::{ChannelEvent, Delivery, MasterChannel},
+    model::{now_timestamp, MESSAGE_DATE_FORMAT},
+};
+use crate::{db::repository::DB, BaseError};
+
+/// How often a node tells the other nodes its sessions are still open.
+pub const PRESENCE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
+/// Sessions of a node that missed its heartbeats for this long no longer count.
+pub const PRESENCE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[async_trait]
+pub trait Presence {
+    /// Registers a new socket for the user, contacts are told when it is the first one.
+    async fn connect(&self, user_id: &str) -> Result<(), BaseError>;
+    /// Drops a socket of the user, the last one going away marks the user offline.
+    async fn disconnect(&self, user_id: &str) -> Result<(), BaseError>;
+    /// Those of `user_ids` with a socket open on any node.
+    async fn online_user_ids(&self, user_ids: &[String]) -> Result<HashSet<String>, BaseError>;
+}
+
+/// Counts sockets per node in the `presence_sessions` table, so users connected
+/// to several nodes only go offline once their last socket anywhere is closed.
+#[derive(Clone)]
+pub struct PresenceImpl {
+    node_id: String,
+    db: Arc<dyn DB + Send + Sync>,
+    master_channel: Arc<dyn MasterChannel + Send + Sync>,
+}
+
+impl PresenceImpl {
+    pub fn new(
+        db: Arc<dyn DB + Send + Sync>,
+        master_channel: Arc<dyn MasterChannel + Send + Sync>,
+    ) -> Self {
+        Self {
+            node_id: Uuid::new_v4().to_string(),
+            db,
+            master_channel,
+        }
+    }
+
+    /// Keeps the sessions of this node alive for as long as the server runs.
+    pub fn spawn_heartbeat(&self) -> JoinHandle<()> {
+        let presence = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(PRESENCE_HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let resp = presence
+                    .db
+                    .heartbeat_presence(&presence.node_id, PRESENCE_TIMEOUT.as_secs_f64())
+                    .await;
+                if let Err(e) = resp {
+                    tracing::warn!("node: {} failed to send heartbeat: {}", presence.node_id, e);
+                }
+            }
+        })
+    }
+
+    async fn publish(&self, data: PresenceData) -> Result<(), BaseError> {
+        let owner_ids = self.db.get_contact_owner_ids(&data.user_id).await?;
+        let delivery = Delivery::from(ChannelEvent::Presence(data));
+        self.master_channel.send_many(&owner_ids, delivery).await;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Presence for PresenceImpl {
+    async fn connect(&self, user_id: &str) -> Result<(), BaseError> {
+        let sessions = self
+            .db
+            .add_presence_session(&self.node_id, user_id, PRESENCE_TIMEOUT.as_secs_f64())
+            .await?;
+        if sessions > 0 {
+            return Ok(());
+        }
+
+        tracing::info!("user: {} is online", user_id);
+        self.publish(PresenceData::online(user_id)).await
+    }
+
+    async fn disconnect(&self, user_id: &str) -> Result<(), BaseError> {
+        self.db
+            .remove_presence_session(&self.node_id, user_id)
+            .await?;
+        // counted after removing, of two sockets closed at once on different
+        // nodes the one counting last sees neither
+        if !self
+            .online_user_ids(&[user_id.to_string()])
+            .await?
+            .is_empty()
+        {
+            return Ok(());
+        }
+
+        tracing::info!("user: {} is offline", user_id);
+        let last_seen_at = now_timestamp();
+        self.db.update_last_seen(user_id, last_seen_at).await?;
+        let last_seen_at = last_seen_at.format(MESSAGE_DATE_FORMAT).to_string();
+        self.publish(PresenceData::offline(user_id, last_seen_at))
+            .await
+    }
+
+    async fn online_user_ids(&self, user_ids: &[String]) -> Result<HashSet<String>, BaseError> {
+        self.db
+            .get_online_user_ids(user_ids, PRESENCE_TIMEOUT.as_secs_f64())
+            .await
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PresenceData {
+    pub user_id: String,
+    pub online: bool,
+    /// Only set once the user went offline.
+    pub last_seen_at: Option<String>,
+}
+
+impl PresenceData {
+    pub fn online(user_id: &str) -> Self {
+        Self {
+            user_id: user_id.to_string(),
+            online: true,
+            last_seen_at: None,
+        }
+    }
+
+    pub fn offline(user_id: &str, last_seen_at: String) -> Self {
+        Self {
+            user_id: user_id.to_string(),
+            online: false,
+            last_seen_at: Some(last_seen_at),
+        }
+    }
+}
@@ -0,0 +1,119 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use super::model::Author;
+
+/// How long a typing indicator stays up without being refreshed by the client.
+pub const TYPING_TTL: Duration = Duration::from_secs(5);
+
+/// How often indicators that were not refreshed are looked for.
+pub const TYPING_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+type TypingKey = (String, String);
+
+/// An indicator that is up, with what is needed to take it down without a lookup.
+#[derive(Debug, Clone)]
+pub struct TypingEntry {
+    pub conversation_id: String,
+    pub author: Author,
+    pub member_ids: Vec<String>,
+    expires_at: Instant,
+}
+
+/// Keeps track of who is typing in which conversation.
+///
+/// Refreshes only push the expiry back, a single sweep takes down the indicators
+/// whose client stopped refreshing them.
+#[derive(Clone, Default)]
+pub struct TypingTracker {
+    typing: Arc<Mutex<HashMap<TypingKey, TypingEntry>>>,
+}
+
+impl TypingTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Pushes the expiry back, `false` when the user is not typing yet and has to `start`.
+    pub fn touch(&self, conversation_id: &str, user_id: &str, now: Instant) -> bool {
+        let mut typing = self.typing.lock().unwrap();
+        let key = (conversation_id.to_string(), user_id.to_string());
+        match typing.get_mut(&key) {
+            Some(entry) => {
+                entry.expires_at = now + TYPING_TTL;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns whether the user just started typing, `false` if a concurrent frame was first.
+    pub fn start(
+        &self,
+        conversation_id: &str,
+        author: Author,
+        member_ids: Vec<String>,
+        now: Instant,
+    ) -> bool {
+        let mut typing = self.typing.lock().unwrap();
+        let key = (conversation_id.to_string(), author.id.clone());
+        let started = !typing.contains_key(&key);
+        typing.insert(
+            key,
+            TypingEntry {
+                conversation_id: conversation_id.to_string(),
+                author,
+                member_ids,
+                expires_at: now + TYPING_TTL,
+            },
+        );
+        started
+    }
+
+    /// Removes and returns the indicators that were not refreshed in time.
+    pub fn sweep(&self, now: Instant) -> Vec<TypingEntry> {
+        let mut typing = self.typing.lock().unwrap();
+        let expired: Vec<TypingKey> = typing
+            .iter()
+            .filter(|(_, entry)| entry.expires_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect();
+        expired
+            .iter()
+            .filter_map(|key| typing.remove(key))
+            .collect()
+    }
+
+    /// Stops the indicator right away, e.g. once the message is sent.
+    pub fn clear(&self, conversation_id: &str, user_id: &str) -> Option<TypingEntry> {
+        let mut typing = self.typing.lock().unwrap();
+        typing.remove(&(conversation_id.to_string(), user_id.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn author(id: &str) -> Author {
+        Author::new(id.into(), id.into(), String::new(), String::new())
+    }
+
+    #[test]
+    fn test_refreshes_push_the_expiry_back() {
+        let tracker = TypingTracker::new();
+        let now = Instant::now();
+        assert!(!tracker.touch("room", "alice", now));
+        assert!(tracker.start("room", author("alice"), vec!["alice".into()], now));
+        assert!(tracker.touch("room", "alice", now + TYPING_TTL / 2));
+
+        assert!(tracker.sweep(now + TYPING_TTL).is_empty());
+        let expired = tracker.sweep(now + TYPING_TTL * 2);
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].author.id, "alice");
+        assert!(tracker.clear("room", "alice").is_none());
+    }
+}
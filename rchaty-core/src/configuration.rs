@@ -1,21 +1,79 @@
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use dotenvy::{dotenv, var};
 
+use crate::util::hmac::SigningKey;
+
 #[derive(Debug, Clone)]
 pub struct CoreConfiguration {
     pub app_redircet_send_verify_email_url: String,
+    pub app_redirect_reset_password_url: String,
+    pub app_oidc_redirect_url: String,
     pub keycloak_admin_username: Arc<String>,
     pub keycloak_admin_password: Arc<String>,
+    pub signing_key: SigningKey,
+    pub signing_previous_keys: Vec<SigningKey>,
     pub keycloak_url: String,
+    pub keycloak_public_url: String,
     pub keycloak_realm: String,
     pub keycloak_client_id: String,
     pub keycloak_client_secret: String,
+    pub keycloak_issuer: Option<String>,
+    pub keycloak_audience: Vec<String>,
+    pub token_introspection_fallback: bool,
+    pub introspection_cache_ttl: u64,
     pub database_host: String,
     pub database_port: u16,
     pub database_user: String,
     pub database_password: String,
     pub database_name: String,
+    pub master_channel: MasterChannelKind,
+    pub session_cookie: SessionCookieConfig,
+    pub signin_throttle: ThrottleConfig,
+    /// Stricter than signin, every forgot password request sends an email.
+    pub forgot_password_throttle: ThrottleConfig,
+    /// Serves the unauthenticated `/ws/mock` routes, never in production.
+    pub mock_routes: bool,
+}
+
+/// Attributes of the cookies holding the session tokens.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SessionCookieConfig {
+    pub domain: Option<String>,
+    pub path: String,
+    /// Only turned off for development over plain http.
+    pub secure: bool,
+}
+
+/// Limits of `Throttle`, applied per client ip and per username or email.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThrottleConfig {
+    /// Attempts allowed per `window`, failed or not.
+    pub max_attempts: u32,
+    pub window: Duration,
+    /// Consecutive failures before a lockout starts.
+    pub lockout_threshold: u32,
+    /// First lockout, doubled with every further failure up to `lockout_max`.
+    pub lockout_base: Duration,
+    pub lockout_max: Duration,
+}
+
+/// Which `MasterChannel` implementation fans messages out to the sockets.
+#[derive(Debug, Clone, PartialEq)]
+pub enum MasterChannelKind {
+    /// In-process only, enough for a single node.
+    Local,
+    /// Relays between nodes with Postgres LISTEN/NOTIFY.
+    Postgres,
+}
+
+impl MasterChannelKind {
+    pub fn from_string(kind: &str) -> Self {
+        match kind {
+            "postgres" => MasterChannelKind::Postgres,
+            _ => MasterChannelKind::Local,
+        }
+    }
 }
 
 impl CoreConfiguration {
@@ -25,6 +83,10 @@ impl CoreConfiguration {
         // app
         let app_redircet_send_verify_email_url = var("APP_REDIRECT_SEND_VERIFY_EMAIL_URL")
             .expect("APP_REDIRECT_SEND_VERIFY_EMAIL_URL must be set");
+        let app_redirect_reset_password_url = var("APP_REDIRECT_RESET_PASSWORD_URL")
+            .unwrap_or("http://0.0.0.0:3000/callback-reset-password".to_string());
+        let app_oidc_redirect_url =
+            var("APP_OIDC_REDIRECT_URL").unwrap_or("http://0.0.0.0:3000/auth/callback".to_string());
 
         // kcloak
         let keycloak_admin_username: String =
@@ -32,10 +94,46 @@ impl CoreConfiguration {
         let keycloak_admin_password =
             var("KEYCLOAK_ADMIN_PASSWORD").expect("KEYCLOAK_ADMIN_PASSWORD must be set");
         let keycloak_url = var("KEYCLOAK_URL").expect("KEYCLOAK_URL must be set");
+        // where browsers reach keycloak for the login page
+        let keycloak_public_url = var("KEYCLOAK_PUBLIC_URL")
+            .ok()
+            .filter(|url| !url.is_empty())
+            .unwrap_or(keycloak_url.clone());
         let keycloak_realm = var("KEYCLOAK_REALM").expect("KEYCLOAK_REALM must be set");
         let keycloak_client_id = var("KEYCLOAK_CLIENT_ID").expect("KEYCLOAK_CLIENT_ID must be set");
         let keycloak_client_secret =
             var("KEYCLOAK_CLIENT_SECRET").expect("KEYCLOAK_CLIENT_SECRET must be set");
+        // only needed when the public issuer differs from KEYCLOAK_URL, e.g. behind docker
+        let keycloak_issuer = var("KEYCLOAK_ISSUER")
+            .ok()
+            .filter(|issuer| !issuer.is_empty());
+        let keycloak_audience = var("KEYCLOAK_AUDIENCE")
+            .unwrap_or("account".to_string())
+            .split(',')
+            .map(|aud| aud.trim().to_string())
+            .collect();
+        // local validation cannot see revoked tokens, introspection can
+        let token_introspection_fallback = var("TOKEN_INTROSPECTION_FALLBACK")
+            .map(|fallback| fallback == "true")
+            .unwrap_or(false);
+        let introspection_cache_ttl = var("INTROSPECTION_CACHE_TTL")
+            .map(|ttl| {
+                ttl.parse()
+                    .expect("INTROSPECTION_CACHE_TTL must be a number")
+            })
+            .unwrap_or(30);
+
+        // signing, the current key first and retired ones kept until their links expire
+        let signing_key = SigningKey::parse(&var("SIGNING_KEY").expect("SIGNING_KEY must be set"))
+            .expect("SIGNING_KEY must look like id:secret");
+        let signing_previous_keys = var("SIGNING_PREVIOUS_KEYS")
+            .unwrap_or_default()
+            .split(',')
+            .filter(|key| !key.trim().is_empty())
+            .map(|key| {
+                SigningKey::parse(key).expect("SIGNING_PREVIOUS_KEYS must look like id:secret")
+            })
+            .collect();
 
         // database
         let database_host = var("DATABASE_HOST").expect("DATABASE_HOST must be set");
@@ -47,19 +145,79 @@ impl CoreConfiguration {
         let database_password = var("DATABASE_PASSWORD").expect("DATABASE_PASSWORD must be set");
         let database_name = var("DATABASE_NAME").expect("DATABASE_NAME must be set");
 
+        // chat
+        let master_channel = var("MASTER_CHANNEL")
+            .map(|kind| MasterChannelKind::from_string(&kind))
+            .unwrap_or(MasterChannelKind::Local);
+
+        // cookies
+        let session_cookie = SessionCookieConfig {
+            domain: var("COOKIE_DOMAIN")
+                .ok()
+                .filter(|domain| !domain.is_empty()),
+            path: var("COOKIE_PATH").unwrap_or("/".to_string()),
+            secure: var("COOKIE_SECURE")
+                .map(|secure| secure != "false")
+                .unwrap_or(true),
+        };
+
+        // throttling
+        let number = |name: &str, default: u64| -> u64 {
+            var(name)
+                .map(|value| {
+                    value
+                        .parse()
+                        .unwrap_or_else(|_| panic!("{} must be a number", name))
+                })
+                .unwrap_or(default)
+        };
+        let signin_throttle = ThrottleConfig {
+            max_attempts: number("SIGNIN_MAX_ATTEMPTS", 10) as u32,
+            window: Duration::from_secs(number("SIGNIN_WINDOW", 60)),
+            lockout_threshold: number("SIGNIN_LOCKOUT_THRESHOLD", 5) as u32,
+            lockout_base: Duration::from_secs(number("SIGNIN_LOCKOUT_BASE", 30)),
+            lockout_max: Duration::from_secs(number("SIGNIN_LOCKOUT_MAX", 15 * 60)),
+        };
+        let forgot_password_throttle = ThrottleConfig {
+            max_attempts: number("FORGOT_PASSWORD_MAX_ATTEMPTS", 3) as u32,
+            window: Duration::from_secs(number("FORGOT_PASSWORD_WINDOW", 15 * 60)),
+            lockout_threshold: number("FORGOT_PASSWORD_LOCKOUT_THRESHOLD", 3) as u32,
+            lockout_base: Duration::from_secs(number("FORGOT_PASSWORD_LOCKOUT_BASE", 15 * 60)),
+            lockout_max: Duration::from_secs(number("FORGOT_PASSWORD_LOCKOUT_MAX", 24 * 60 * 60)),
+        };
+
+        // development
+        let mock_routes = var("MOCK_ROUTES")
+            .map(|mock| mock == "true")
+            .unwrap_or(false);
+
         CoreConfiguration {
             app_redircet_send_verify_email_url,
+            app_redirect_reset_password_url,
+            app_oidc_redirect_url,
             keycloak_admin_username: Arc::new(keycloak_admin_username),
             keycloak_admin_password: Arc::new(keycloak_admin_password),
+            signing_key,
+            signing_previous_keys,
             keycloak_url,
+            keycloak_public_url,
             keycloak_realm,
             keycloak_client_id,
             keycloak_client_secret,
+            keycloak_issuer,
+            keycloak_audience,
+            token_introspection_fallback,
+            introspection_cache_ttl,
             database_host,
             database_port,
             database_user,
             database_password,
             database_name,
+            master_channel,
+            session_cookie,
+            signin_throttle,
+            forgot_password_throttle,
+            mock_routes,
         }
     }
 
@@ -0,0 +1,102 @@
+use tokio_postgres::Client;
+
+use crate::BaseError;
+
+pub struct Migration {
+    pub version: i32,
+    pub name: &'static str,
+    pub sql: &'static str,
+}
+
+macro_rules! migration {
+    ($version:literal, $name:literal, $file:literal) => {
+        Migration {
+            version: $version,
+            name: $name,
+            sql: include_str!(concat!("../../migrations/", $file)),
+        }
+    };
+}
+
+/// Applied in order, each once. Every script is idempotent so databases set up
+/// before the migrations were tracked pick them up without failing.
+pub const MIGRATIONS: &[Migration] = &[
+    migration!(0, "users_contacts", "00_users_contacts.sql"),
+    migration!(1, "messages", "01_messages.sql"),
+    migration!(2, "conversations", "02_conversations.sql"),
+    migration!(3, "presence", "03_presence.sql"),
+    migration!(4, "token_nonces", "04_token_nonces.sql"),
+    migration!(5, "contact_requests", "05_contact_requests.sql"),
+    migration!(6, "user_search", "06_user_search.sql"),
+    migration!(7, "user_blocks", "07_user_blocks.sql"),
+    migration!(8, "contact_list_preview", "08_contact_list_preview.sql"),
+    migration!(9, "presence_sessions", "09_presence_sessions.sql"),
+    migration!(10, "message_seq", "10_message_seq.sql"),
+    migration!(11, "message_receipts", "11_message_receipts.sql"),
+];
+
+/// Serializes nodes starting at the same time, any constant shared by them works.
+const MIGRATION_LOCK_KEY: i64 = 0x7263_6861_7479;
+
+/// Applies the migrations missing from `schema_migrations`, each in its own transaction.
+pub async fn migrate(client: &mut Client) -> Result<(), BaseError> {
+    client
+        .execute("SELECT pg_advisory_lock($1)", &[&MIGRATION_LOCK_KEY])
+        .await?;
+    let resp = apply_missing(client).await;
+    client
+        .execute("SELECT pg_advisory_unlock($1)", &[&MIGRATION_LOCK_KEY])
+        .await?;
+    resp
+}
+
+async fn apply_missing(client: &mut Client) -> Result<(), BaseError> {
+    client
+        .batch_execute(
+            "CREATE TABLE IF NOT EXISTS schema_migrations (
+                version INTEGER PRIMARY KEY,
+                name TEXT NOT NULL,
+                applied_at TIMESTAMP NOT NULL DEFAULT now()
+            )",
+        )
+        .await?;
+    let applied: Vec<i32> = client
+        .query("SELECT version FROM schema_migrations", &[])
+        .await?
+        .iter()
+        .map(|row| row.get(0))
+        .collect();
+
+    for migration in MIGRATIONS {
+        if applied.contains(&migration.version) {
+            continue;
+        }
+        tracing::info!(
+            "applying migration {} {}",
+            migration.version,
+            migration.name
+        );
+        let tx = client.transaction().await?;
+        tx.batch_execute(migration.sql).await?;
+        tx.execute(
+            "INSERT INTO schema_migrations (version, name) VALUES ($1, $2)",
+            &[&migration.version, &migration.name],
+        )
+        .await?;
+        tx.commit().await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_migrations_are_ordered_by_version() {
+        assert!(MIGRATIONS
+            .windows(2)
+            .all(|pair| pair[0].version < pair[1].version));
+    }
+}
@@ -1,10 +1,29 @@
-use std::{sync::Arc, usize};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
 use async_trait::async_trait;
+use chrono::{DateTime, NaiveDateTime};
 use keycloak::types::UserRepresentation;
+use tokio_postgres::Row;
 use uuid::Uuid;
 
-use crate::{configuration::CoreConfiguration, service::service_contact::ContactItem, BaseError};
+use crate::{
+    chatchannel::model::{
+        Author, ContactRequest, ContentType, MessageData, MessageStatus, Restriction,
+        MESSAGE_DATE_FORMAT,
+    },
+    configuration::CoreConfiguration,
+    db::migration::migrate,
+    service::{
+        service_contact::{ContactItem, RestrictedUser, MESSAGE_PREVIEW_LENGTH},
+        service_conversation::{ConversationItem, ConversationKind},
+        service_search::UserItem,
+    },
+    util::hmac::NonceStore,
+    BaseError,
+};
 
 #[derive(Clone, Debug)]
 pub struct DBConfig {
@@ -32,17 +51,21 @@ pub struct DBImpl {
     client: tokio_postgres::Client,
 }
 
+impl DBConfig {
+    pub fn connection_string(&self) -> String {
+        format!(
+            "host={} port={} user={} password={} dbname={}",
+            self.host, self.port, self.user, self.password, self.database
+        )
+    }
+}
+
 impl DBImpl {
     pub async fn connect(config: DBConfig) -> Self {
-        let (client, connection) = tokio_postgres::connect(
-            &format!(
-                "host={} port={} user={} password={} dbname={}",
-                config.host, config.port, config.user, config.password, config.database
-            ),
-            tokio_postgres::NoTls,
-        )
-        .await
-        .expect("failed to connect to database");
+        let (mut client, connection) =
+            tokio_postgres::connect(&config.connection_string(), tokio_postgres::NoTls)
+                .await
+                .expect("failed to connect to database");
 
         // The connection object performs the actual communication with the database,
         // so spawn it off to run on its own.
@@ -52,6 +75,10 @@ impl DBImpl {
             }
         });
 
+        migrate(&mut client)
+            .await
+            .expect("failed to migrate database");
+
         DBImpl {
             config: Arc::new(config),
             client,
@@ -63,7 +90,230 @@ impl DBImpl {
 pub trait DB {
     async fn save_user(&self, user: &UserRepresentation) -> Result<(), BaseError>;
     async fn update_verified_email(&self, user_id: &str) -> Result<(), BaseError>;
+    /// Records a single-use token nonce, `false` when it was already consumed.
+    async fn consume_token_nonce(
+        &self,
+        nonce: &str,
+        expires_at: NaiveDateTime,
+    ) -> Result<bool, BaseError>;
+    /// Contacts with the latest message and unread count of their direct conversation,
+    /// most recent activity first.
     async fn get_contacts_by_user_id(&self, user_id: &str) -> Result<Vec<ContactItem>, BaseError>;
+    async fn get_contact_owner_ids(&self, friend_id: &str) -> Result<Vec<String>, BaseError>;
+    async fn update_contact_name(
+        &self,
+        user_id: &str,
+        friend_id: &str,
+        name: &str,
+    ) -> Result<(), BaseError>;
+    async fn delete_contact(&self, user_id: &str, friend_id: &str) -> Result<(), BaseError>;
+    async fn is_contact(&self, user_id: &str, friend_id: &str) -> Result<bool, BaseError>;
+    /// The name `user_id` gave the contact, `None` when `friend_id` is not a contact.
+    async fn get_contact_name(
+        &self,
+        user_id: &str,
+        friend_id: &str,
+    ) -> Result<Option<String>, BaseError>;
+    async fn get_user_id_by_email(&self, email: &str) -> Result<Option<String>, BaseError>;
+    /// Users matching the lowercase `LIKE` prefix `pattern`, other than `user_id` and its contacts.
+    async fn search_users(
+        &self,
+        user_id: &str,
+        pattern: &str,
+        limit: i64,
+    ) -> Result<Vec<UserItem>, BaseError>;
+    /// `None` when the sender already has a pending request to the recipient.
+    async fn insert_contact_request(
+        &self,
+        id: &str,
+        sender_id: &str,
+        recipient_id: &str,
+    ) -> Result<Option<ContactRequest>, BaseError>;
+    async fn get_contact_request(&self, id: &str) -> Result<Option<ContactRequest>, BaseError>;
+    async fn find_contact_request(
+        &self,
+        sender_id: &str,
+        recipient_id: &str,
+    ) -> Result<Option<ContactRequest>, BaseError>;
+    async fn get_incoming_contact_requests(
+        &self,
+        recipient_id: &str,
+    ) -> Result<Vec<ContactRequest>, BaseError>;
+    async fn get_outgoing_contact_requests(
+        &self,
+        sender_id: &str,
+    ) -> Result<Vec<ContactRequest>, BaseError>;
+    /// Removes the request and adds each user to the other's contacts, in one statement.
+    async fn accept_contact_request(&self, id: &str) -> Result<(), BaseError>;
+    async fn delete_contact_request(&self, id: &str) -> Result<(), BaseError>;
+    async fn delete_contact_requests_between(
+        &self,
+        user_id: &str,
+        other_id: &str,
+    ) -> Result<(), BaseError>;
+    /// Sets what `user_id` restricts `target_id` to, replacing any previous restriction.
+    async fn set_restriction(
+        &self,
+        user_id: &str,
+        target_id: &str,
+        restriction: Restriction,
+    ) -> Result<(), BaseError>;
+    async fn remove_restriction(
+        &self,
+        user_id: &str,
+        target_id: &str,
+        restriction: Restriction,
+    ) -> Result<(), BaseError>;
+    async fn get_restricted_users(&self, user_id: &str) -> Result<Vec<RestrictedUser>, BaseError>;
+    /// What each of `user_ids` restricts `author_id` to, users without a restriction left out.
+    async fn get_restrictions_against(
+        &self,
+        author_id: &str,
+        user_ids: &[String],
+    ) -> Result<HashMap<String, Restriction>, BaseError>;
+    /// Whether either user blocked the other.
+    async fn is_blocked(&self, user_id: &str, other_id: &str) -> Result<bool, BaseError>;
+    async fn update_last_seen(
+        &self,
+        user_id: &str,
+        last_seen_at: NaiveDateTime,
+    ) -> Result<(), BaseError>;
+    /// Counts one more socket of the user on the node, returning the sockets it had
+    /// on every node with a heartbeat within `alive_secs` before this one.
+    async fn add_presence_session(
+        &self,
+        node_id: &str,
+        user_id: &str,
+        alive_secs: f64,
+    ) -> Result<i64, BaseError>;
+    async fn remove_presence_session(&self, node_id: &str, user_id: &str) -> Result<(), BaseError>;
+    /// Those of `user_ids` with a socket on a node with a heartbeat within `alive_secs`.
+    async fn get_online_user_ids(
+        &self,
+        user_ids: &[String],
+        alive_secs: f64,
+    ) -> Result<HashSet<String>, BaseError>;
+    /// Keeps the node's sessions alive and drops the closed ones and those of nodes
+    /// without a heartbeat within `alive_secs`.
+    async fn heartbeat_presence(&self, node_id: &str, alive_secs: f64) -> Result<(), BaseError>;
+    /// Users saved before usernames were stored, by id after `after_id`.
+    async fn get_user_ids_without_username(
+        &self,
+        after_id: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<String>, BaseError>;
+    async fn update_username(&self, user_id: &str, username: &str) -> Result<(), BaseError>;
+    async fn insert_message(&self, message: &MessageData) -> Result<MessageData, BaseError>;
+    async fn get_messages_by_conversation_id(
+        &self,
+        conversation_id: &str,
+    ) -> Result<Vec<MessageData>, BaseError>;
+    async fn get_message_by_id(&self, id: &str) -> Result<MessageData, BaseError>;
+    /// A page of messages sent before `before_id` as `viewer_id` sees them, leaving out
+    /// authors they blocked.
+    async fn get_messages_before(
+        &self,
+        conversation_id: &str,
+        viewer_id: &str,
+        before_id: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<MessageData>, BaseError>;
+    /// Records that `user_id` received or read the message, returning it when the
+    /// status its author sees changed as a result.
+    async fn update_message_receipt(
+        &self,
+        id: &str,
+        user_id: &str,
+        status: &MessageStatus,
+    ) -> Result<Option<MessageData>, BaseError>;
+    /// Messages of the other members `user_id` has not read yet.
+    async fn count_unread_messages(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+    ) -> Result<i64, BaseError>;
+    async fn get_author_by_user_id(&self, user_id: &str) -> Result<Author, BaseError>;
+    async fn create_conversation(
+        &self,
+        id: &str,
+        kind: &ConversationKind,
+        name: Option<&str>,
+        created_by: &str,
+        member_ids: &[String],
+    ) -> Result<ConversationItem, BaseError>;
+    async fn get_conversation_by_id(&self, id: &str) -> Result<ConversationItem, BaseError>;
+    async fn get_conversations_by_user_id(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<ConversationItem>, BaseError>;
+    async fn get_conversation_member_ids(
+        &self,
+        conversation_id: &str,
+    ) -> Result<Vec<String>, BaseError>;
+}
+
+const MESSAGE_COLUMNS: &str = "id, conversation_id, author_id, author_username, author_email, \
+     author_avatar, content, content_type, status, created_at";
+
+/// `MESSAGE_COLUMNS` of `messages m` as the viewer `$2` sees them: the status of a
+/// message someone else wrote is the viewer's receipt `r`, not the author's summary.
+const VIEWER_MESSAGE_COLUMNS: &str = "m.id, m.conversation_id, m.author_id, m.author_username, \
+     m.author_email, m.author_avatar, m.content, m.content_type, \
+     CASE WHEN m.author_id = $2 THEN m.status ELSE coalesce(r.status, 'sent') END AS status, \
+     m.created_at";
+
+fn message_from_row(row: &Row) -> MessageData {
+    let author = Author::new(
+        row.get::<&str, Uuid>("author_id").to_string(),
+        row.get("author_username"),
+        row.get("author_email"),
+        row.get("author_avatar"),
+    );
+    MessageData::new(
+        row.get::<&str, Uuid>("id").to_string(),
+        row.get::<&str, Uuid>("conversation_id").to_string(),
+        author,
+        row.get("content"),
+        ContentType::from_string(row.get("content_type")),
+        row.get::<&str, NaiveDateTime>("created_at")
+            .format(MESSAGE_DATE_FORMAT)
+            .to_string(),
+        MessageStatus::from_string(row.get("status")),
+    )
+}
+
+/// Selects contact requests from `source` with the names of both users.
+fn contact_request_query(source: &str, filter: &str) -> String {
+    format!(
+        "SELECT r.id, r.sender_id, r.recipient_id, r.created_at, \
+         trim(coalesce(s.first_name, '') || ' ' || coalesce(s.last_name, '')) AS sender_name, \
+         trim(coalesce(t.first_name, '') || ' ' || coalesce(t.last_name, '')) AS recipient_name \
+         FROM {source} r \
+         LEFT JOIN users s ON s.user_id = r.sender_id \
+         LEFT JOIN users t ON t.user_id = r.recipient_id \
+         {filter}"
+    )
+}
+
+fn contact_request_from_row(row: &Row) -> ContactRequest {
+    ContactRequest {
+        id: row.get::<&str, Uuid>("id").to_string(),
+        sender_id: row.get::<&str, Uuid>("sender_id").to_string(),
+        sender_name: row.get("sender_name"),
+        recipient_id: row.get::<&str, Uuid>("recipient_id").to_string(),
+        recipient_name: row.get("recipient_name"),
+        created_at: row.get("created_at"),
+    }
+}
+
+fn conversation_from_row(row: &Row) -> ConversationItem {
+    ConversationItem {
+        id: row.get::<&str, Uuid>("id").to_string(),
+        kind: ConversationKind::from_string(row.get("kind")),
+        name: row.get("name"),
+        created_by: row.get::<&str, Uuid>("created_by").to_string(),
+        created_at: row.get("created_at"),
+    }
 }
 
 #[async_trait]
@@ -77,8 +327,15 @@ impl DB for DBImpl {
         let client = &self.client;
         let row_affected = client
             .execute(
-                "INSERT INTO users (user_id, first_name, last_name, email) VALUES ($1, $2, $3, $4)",
-                &[&user_id, &user.first_name, &user.last_name, &user.email],
+                "INSERT INTO users (user_id, username, first_name, last_name, email) \
+                 VALUES ($1, $2, $3, $4, $5)",
+                &[
+                    &user_id,
+                    &user.username,
+                    &user.first_name,
+                    &user.last_name,
+                    &user.email,
+                ],
             )
             .await?;
 
@@ -91,6 +348,28 @@ impl DB for DBImpl {
         Ok(())
     }
 
+    async fn consume_token_nonce(
+        &self,
+        nonce: &str,
+        expires_at: NaiveDateTime,
+    ) -> Result<bool, BaseError> {
+        let client = &self.client;
+        client
+            .execute(
+                "DELETE FROM consumed_token_nonces WHERE expires_at < NOW()",
+                &[],
+            )
+            .await?;
+        let row_affected = client
+            .execute(
+                "INSERT INTO consumed_token_nonces (nonce, expires_at) VALUES ($1, $2)
+                ON CONFLICT (nonce) DO NOTHING",
+                &[&nonce, &expires_at],
+            )
+            .await?;
+        Ok(row_affected == 1)
+    }
+
     async fn update_verified_email(&self, user_id: &str) -> Result<(), BaseError> {
         let user_id = Uuid::parse_str(user_id)?;
         let client = &self.client;
@@ -113,24 +392,914 @@ impl DB for DBImpl {
     async fn get_contacts_by_user_id(&self, user_id: &str) -> Result<Vec<ContactItem>, BaseError> {
         let user_id = Uuid::parse_str(user_id)?;
         let client = &self.client;
+        // messages of a blocked contact are left out as they were never delivered
         let rows = client
-            .query("SELECT * FROM contacts WHERE user_id = $1", &[&user_id])
+            .query(
+                "SELECT c.id, c.user_id, c.friend_id, c.name, c.created_at, u.last_seen_at, \
+                 b.kind, last_message.content, last_message.created_at, unread.count \
+                 FROM contacts c LEFT JOIN users u ON u.user_id = c.friend_id \
+                 LEFT JOIN user_blocks b ON b.user_id = c.user_id AND b.blocked_id = c.friend_id \
+                 LEFT JOIN LATERAL (SELECT cv.id FROM conversations cv \
+                 JOIN conversation_members mu \
+                 ON mu.conversation_id = cv.id AND mu.user_id = c.user_id \
+                 JOIN conversation_members mf \
+                 ON mf.conversation_id = cv.id AND mf.user_id = c.friend_id \
+                 WHERE cv.kind = 'direct' LIMIT 1) direct ON true \
+                 LEFT JOIN LATERAL (SELECT left(m.content, $2) AS content, m.created_at \
+                 FROM messages m WHERE m.conversation_id = direct.id \
+                 AND (b.kind IS DISTINCT FROM 'block' OR m.author_id = c.user_id) \
+                 ORDER BY m.seq DESC LIMIT 1) last_message ON true \
+                 LEFT JOIN LATERAL (SELECT count(*) AS count FROM messages m \
+                 WHERE m.conversation_id = direct.id AND m.author_id = c.friend_id \
+                 AND NOT EXISTS (SELECT 1 FROM message_receipts r \
+                 WHERE r.message_id = m.id AND r.user_id = c.user_id AND r.status = 'read') \
+                 AND b.kind IS DISTINCT FROM 'block') unread ON true \
+                 WHERE c.user_id = $1 \
+                 ORDER BY coalesce(last_message.created_at, c.created_at) DESC, c.id",
+                &[&user_id, &MESSAGE_PREVIEW_LENGTH],
+            )
             .await
-            .map_err(|e| BaseError::from(e))?;
+            .map_err(BaseError::from)?;
 
         let res = rows
             .iter()
-            .map(|row| {
-                return ContactItem {
-                    id: row.get(0),
-                    user_id: row.get::<usize, Uuid>(1).to_string(),
-                    friend_id: row.get::<usize, Uuid>(2).to_string(),
-                    name: row.get(3),
-                    created_at: row.get(4),
-                };
+            .map(|row| ContactItem {
+                id: row.get(0),
+                user_id: row.get::<usize, Uuid>(1).to_string(),
+                friend_id: row.get::<usize, Uuid>(2).to_string(),
+                name: row.get(3),
+                created_at: row.get(4),
+                last_seen_at: row.get(5),
+                online: false,
+                restriction: row
+                    .get::<usize, Option<&str>>(6)
+                    .and_then(|kind| Restriction::parse(kind).ok()),
+                last_message: row.get(7),
+                last_message_at: row.get(8),
+                unread_count: row.get::<usize, Option<i64>>(9).unwrap_or_default(),
             })
             .collect();
 
         Ok(res)
     }
+
+    async fn update_contact_name(
+        &self,
+        user_id: &str,
+        friend_id: &str,
+        name: &str,
+    ) -> Result<(), BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let friend_id = Uuid::parse_str(friend_id)?;
+        let client = &self.client;
+        let row_affected = client
+            .execute(
+                "UPDATE contacts SET name = $3 WHERE user_id = $1 AND friend_id = $2",
+                &[&user_id, &friend_id, &name],
+            )
+            .await?;
+
+        if row_affected == 0 {
+            return Err(BaseError::new(404, "contact not found"));
+        }
+        Ok(())
+    }
+
+    async fn delete_contact(&self, user_id: &str, friend_id: &str) -> Result<(), BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let friend_id = Uuid::parse_str(friend_id)?;
+        let client = &self.client;
+        let row_affected = client
+            .execute(
+                "DELETE FROM contacts WHERE user_id = $1 AND friend_id = $2",
+                &[&user_id, &friend_id],
+            )
+            .await?;
+
+        if row_affected == 0 {
+            return Err(BaseError::new(404, "contact not found"));
+        }
+        Ok(())
+    }
+
+    async fn get_contact_owner_ids(&self, friend_id: &str) -> Result<Vec<String>, BaseError> {
+        let friend_id = Uuid::parse_str(friend_id)?;
+        let client = &self.client;
+        let rows = client
+            .query(
+                "SELECT user_id FROM contacts WHERE friend_id = $1",
+                &[&friend_id],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| row.get::<usize, Uuid>(0).to_string())
+            .collect())
+    }
+
+    async fn is_contact(&self, user_id: &str, friend_id: &str) -> Result<bool, BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let friend_id = Uuid::parse_str(friend_id)?;
+        let client = &self.client;
+        let row = client
+            .query_opt(
+                "SELECT 1 FROM contacts WHERE user_id = $1 AND friend_id = $2",
+                &[&user_id, &friend_id],
+            )
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn get_contact_name(
+        &self,
+        user_id: &str,
+        friend_id: &str,
+    ) -> Result<Option<String>, BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let friend_id = Uuid::parse_str(friend_id)?;
+        let client = &self.client;
+        let row = client
+            .query_opt(
+                "SELECT name FROM contacts WHERE user_id = $1 AND friend_id = $2",
+                &[&user_id, &friend_id],
+            )
+            .await?;
+        Ok(row.map(|row| row.get(0)))
+    }
+
+    async fn get_user_id_by_email(&self, email: &str) -> Result<Option<String>, BaseError> {
+        let client = &self.client;
+        let row = client
+            .query_opt(
+                "SELECT user_id FROM users WHERE lower(email) = lower($1)",
+                &[&email],
+            )
+            .await?;
+        Ok(row.map(|row| row.get::<usize, Uuid>(0).to_string()))
+    }
+
+    async fn search_users(
+        &self,
+        user_id: &str,
+        pattern: &str,
+        limit: i64,
+    ) -> Result<Vec<UserItem>, BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let client = &self.client;
+        // each condition can use its own prefix index
+        let rows = client
+            .query(
+                "SELECT u.user_id, coalesce(u.username, '') AS username, \
+                 trim(coalesce(u.first_name, '') || ' ' || coalesce(u.last_name, '')) AS name \
+                 FROM users u \
+                 WHERE (lower(u.username) LIKE $2 OR lower(u.first_name) LIKE $2 \
+                 OR lower(u.last_name) LIKE $2 OR lower(u.email) LIKE $2) \
+                 AND u.user_id <> $1 \
+                 AND NOT EXISTS (SELECT 1 FROM contacts c \
+                 WHERE c.user_id = $1 AND c.friend_id = u.user_id) \
+                 AND NOT EXISTS (SELECT 1 FROM user_blocks b WHERE b.kind = 'block' \
+                 AND ((b.user_id = $1 AND b.blocked_id = u.user_id) \
+                 OR (b.user_id = u.user_id AND b.blocked_id = $1))) \
+                 ORDER BY name, username LIMIT $3",
+                &[&user_id, &pattern, &limit],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| UserItem {
+                user_id: row.get::<usize, Uuid>(0).to_string(),
+                username: row.get(1),
+                name: row.get(2),
+            })
+            .collect())
+    }
+
+    async fn insert_contact_request(
+        &self,
+        id: &str,
+        sender_id: &str,
+        recipient_id: &str,
+    ) -> Result<Option<ContactRequest>, BaseError> {
+        let id = Uuid::parse_str(id)?;
+        let sender_id = Uuid::parse_str(sender_id)?;
+        let recipient_id = Uuid::parse_str(recipient_id)?;
+        let client = &self.client;
+        let row = client
+            .query_opt(
+                &format!(
+                    "WITH inserted AS (INSERT INTO contact_requests (id, sender_id, recipient_id) \
+                     VALUES ($1, $2, $3) ON CONFLICT DO NOTHING RETURNING *) {}",
+                    contact_request_query("inserted", "")
+                ),
+                &[&id, &sender_id, &recipient_id],
+            )
+            .await?;
+        Ok(row.as_ref().map(contact_request_from_row))
+    }
+
+    async fn get_contact_request(&self, id: &str) -> Result<Option<ContactRequest>, BaseError> {
+        let id = Uuid::parse_str(id)?;
+        let client = &self.client;
+        let row = client
+            .query_opt(
+                &contact_request_query("contact_requests", "WHERE r.id = $1"),
+                &[&id],
+            )
+            .await?;
+        Ok(row.as_ref().map(contact_request_from_row))
+    }
+
+    async fn find_contact_request(
+        &self,
+        sender_id: &str,
+        recipient_id: &str,
+    ) -> Result<Option<ContactRequest>, BaseError> {
+        let sender_id = Uuid::parse_str(sender_id)?;
+        let recipient_id = Uuid::parse_str(recipient_id)?;
+        let client = &self.client;
+        let row = client
+            .query_opt(
+                &contact_request_query(
+                    "contact_requests",
+                    "WHERE r.sender_id = $1 AND r.recipient_id = $2",
+                ),
+                &[&sender_id, &recipient_id],
+            )
+            .await?;
+        Ok(row.as_ref().map(contact_request_from_row))
+    }
+
+    async fn get_incoming_contact_requests(
+        &self,
+        recipient_id: &str,
+    ) -> Result<Vec<ContactRequest>, BaseError> {
+        let recipient_id = Uuid::parse_str(recipient_id)?;
+        let client = &self.client;
+        let rows = client
+            .query(
+                &contact_request_query(
+                    "contact_requests",
+                    "WHERE r.recipient_id = $1 ORDER BY r.created_at DESC",
+                ),
+                &[&recipient_id],
+            )
+            .await?;
+        Ok(rows.iter().map(contact_request_from_row).collect())
+    }
+
+    async fn get_outgoing_contact_requests(
+        &self,
+        sender_id: &str,
+    ) -> Result<Vec<ContactRequest>, BaseError> {
+        let sender_id = Uuid::parse_str(sender_id)?;
+        let client = &self.client;
+        let rows = client
+            .query(
+                &contact_request_query(
+                    "contact_requests",
+                    "WHERE r.sender_id = $1 ORDER BY r.created_at DESC",
+                ),
+                &[&sender_id],
+            )
+            .await?;
+        Ok(rows.iter().map(contact_request_from_row).collect())
+    }
+
+    async fn accept_contact_request(&self, id: &str) -> Result<(), BaseError> {
+        let id = Uuid::parse_str(id)?;
+        let client = &self.client;
+        client
+            .execute(
+                "WITH accepted AS (DELETE FROM contact_requests WHERE id = $1 \
+                 RETURNING sender_id, recipient_id), \
+                 pairs AS (SELECT sender_id AS user_id, recipient_id AS friend_id FROM accepted \
+                 UNION ALL SELECT recipient_id, sender_id FROM accepted) \
+                 INSERT INTO contacts (user_id, friend_id, name, created_at) \
+                 SELECT p.user_id, p.friend_id, \
+                 trim(coalesce(u.first_name, '') || ' ' || coalesce(u.last_name, '')), now() \
+                 FROM pairs p LEFT JOIN users u ON u.user_id = p.friend_id \
+                 WHERE NOT EXISTS (SELECT 1 FROM contacts c \
+                 WHERE c.user_id = p.user_id AND c.friend_id = p.friend_id)",
+                &[&id],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn delete_contact_request(&self, id: &str) -> Result<(), BaseError> {
+        let id = Uuid::parse_str(id)?;
+        let client = &self.client;
+        let row_affected = client
+            .execute("DELETE FROM contact_requests WHERE id = $1", &[&id])
+            .await?;
+
+        if row_affected == 0 {
+            return Err(BaseError {
+                code: 404,
+                messages: "contact request not found".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    async fn delete_contact_requests_between(
+        &self,
+        user_id: &str,
+        other_id: &str,
+    ) -> Result<(), BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let other_id = Uuid::parse_str(other_id)?;
+        let client = &self.client;
+        client
+            .execute(
+                "DELETE FROM contact_requests WHERE (sender_id = $1 AND recipient_id = $2) \
+                 OR (sender_id = $2 AND recipient_id = $1)",
+                &[&user_id, &other_id],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn set_restriction(
+        &self,
+        user_id: &str,
+        target_id: &str,
+        restriction: Restriction,
+    ) -> Result<(), BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let target_id = Uuid::parse_str(target_id)?;
+        let client = &self.client;
+        client
+            .execute(
+                "INSERT INTO user_blocks (user_id, blocked_id, kind) VALUES ($1, $2, $3) \
+                 ON CONFLICT (user_id, blocked_id) \
+                 DO UPDATE SET kind = EXCLUDED.kind, created_at = now()",
+                &[&user_id, &target_id, &restriction.to_string()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn remove_restriction(
+        &self,
+        user_id: &str,
+        target_id: &str,
+        restriction: Restriction,
+    ) -> Result<(), BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let target_id = Uuid::parse_str(target_id)?;
+        let client = &self.client;
+        let row_affected = client
+            .execute(
+                "DELETE FROM user_blocks WHERE user_id = $1 AND blocked_id = $2 AND kind = $3",
+                &[&user_id, &target_id, &restriction.to_string()],
+            )
+            .await?;
+
+        if row_affected == 0 {
+            let messages = match restriction {
+                Restriction::Block => "user is not blocked",
+                Restriction::Mute => "user is not muted",
+            };
+            return Err(BaseError::new(404, messages));
+        }
+        Ok(())
+    }
+
+    async fn get_restricted_users(&self, user_id: &str) -> Result<Vec<RestrictedUser>, BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let client = &self.client;
+        let rows = client
+            .query(
+                "SELECT b.blocked_id, \
+                 trim(coalesce(u.first_name, '') || ' ' || coalesce(u.last_name, '')), \
+                 b.kind, b.created_at \
+                 FROM user_blocks b LEFT JOIN users u ON u.user_id = b.blocked_id \
+                 WHERE b.user_id = $1 ORDER BY b.created_at DESC",
+                &[&user_id],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                Some(RestrictedUser {
+                    user_id: row.get::<usize, Uuid>(0).to_string(),
+                    name: row.get(1),
+                    restriction: Restriction::parse(row.get(2)).ok()?,
+                    created_at: row.get(3),
+                })
+            })
+            .collect())
+    }
+
+    async fn get_restrictions_against(
+        &self,
+        author_id: &str,
+        user_ids: &[String],
+    ) -> Result<HashMap<String, Restriction>, BaseError> {
+        let author_id = Uuid::parse_str(author_id)?;
+        let user_ids = user_ids
+            .iter()
+            .map(|user_id| Uuid::parse_str(user_id))
+            .collect::<Result<Vec<Uuid>, _>>()?;
+        let client = &self.client;
+        let rows = client
+            .query(
+                "SELECT user_id, kind FROM user_blocks \
+                 WHERE blocked_id = $1 AND user_id = ANY($2)",
+                &[&author_id, &user_ids],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .filter_map(|row| {
+                let restriction = Restriction::parse(row.get(1)).ok()?;
+                Some((row.get::<usize, Uuid>(0).to_string(), restriction))
+            })
+            .collect())
+    }
+
+    async fn is_blocked(&self, user_id: &str, other_id: &str) -> Result<bool, BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let other_id = Uuid::parse_str(other_id)?;
+        let client = &self.client;
+        let row = client
+            .query_opt(
+                "SELECT 1 FROM user_blocks WHERE kind = 'block' \
+                 AND ((user_id = $1 AND blocked_id = $2) OR (user_id = $2 AND blocked_id = $1))",
+                &[&user_id, &other_id],
+            )
+            .await?;
+        Ok(row.is_some())
+    }
+
+    async fn update_last_seen(
+        &self,
+        user_id: &str,
+        last_seen_at: NaiveDateTime,
+    ) -> Result<(), BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let client = &self.client;
+        client
+            .execute(
+                "UPDATE users SET last_seen_at = $2 WHERE user_id = $1",
+                &[&user_id, &last_seen_at],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn add_presence_session(
+        &self,
+        node_id: &str,
+        user_id: &str,
+        alive_secs: f64,
+    ) -> Result<i64, BaseError> {
+        let node_id = Uuid::parse_str(node_id)?;
+        let user_id = Uuid::parse_str(user_id)?;
+        let client = &self.client;
+        // the select reads the rows as they were before the insert, so of two
+        // sockets opened at once on different nodes at least one sees no other
+        let row = client
+            .query_one(
+                "WITH added AS ( \
+                     INSERT INTO presence_sessions (node_id, user_id, sessions, heartbeat_at) \
+                     VALUES ($1, $2, 1, now()) \
+                     ON CONFLICT (user_id, node_id) DO UPDATE \
+                     SET sessions = presence_sessions.sessions + 1, heartbeat_at = now() \
+                 ) \
+                 SELECT COALESCE(SUM(sessions), 0) FROM presence_sessions \
+                 WHERE user_id = $2 AND heartbeat_at > now() - make_interval(secs => $3)",
+                &[&node_id, &user_id, &alive_secs],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    async fn remove_presence_session(&self, node_id: &str, user_id: &str) -> Result<(), BaseError> {
+        let node_id = Uuid::parse_str(node_id)?;
+        let user_id = Uuid::parse_str(user_id)?;
+        let client = &self.client;
+        client
+            .execute(
+                "UPDATE presence_sessions SET sessions = GREATEST(sessions - 1, 0) \
+                 WHERE node_id = $1 AND user_id = $2",
+                &[&node_id, &user_id],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn get_online_user_ids(
+        &self,
+        user_ids: &[String],
+        alive_secs: f64,
+    ) -> Result<HashSet<String>, BaseError> {
+        let user_ids = user_ids
+            .iter()
+            .map(|user_id| Uuid::parse_str(user_id))
+            .collect::<Result<Vec<Uuid>, _>>()?;
+        let client = &self.client;
+        let rows = client
+            .query(
+                "SELECT DISTINCT user_id FROM presence_sessions \
+                 WHERE user_id = ANY($1) AND sessions > 0 \
+                 AND heartbeat_at > now() - make_interval(secs => $2)",
+                &[&user_ids, &alive_secs],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| row.get::<usize, Uuid>(0).to_string())
+            .collect())
+    }
+
+    async fn heartbeat_presence(&self, node_id: &str, alive_secs: f64) -> Result<(), BaseError> {
+        let node_id = Uuid::parse_str(node_id)?;
+        let client = &self.client;
+        client
+            .execute(
+                "UPDATE presence_sessions SET heartbeat_at = now() \
+                 WHERE node_id = $1 AND sessions > 0",
+                &[&node_id],
+            )
+            .await?;
+        client
+            .execute(
+                "DELETE FROM presence_sessions WHERE sessions = 0 \
+                 OR heartbeat_at <= now() - make_interval(secs => $1)",
+                &[&alive_secs],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn get_user_ids_without_username(
+        &self,
+        after_id: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<String>, BaseError> {
+        let after_id = after_id.map(Uuid::parse_str).transpose()?;
+        let client = &self.client;
+        let rows = client
+            .query(
+                "SELECT user_id FROM users \
+                 WHERE username IS NULL AND ($1::uuid IS NULL OR user_id > $1) \
+                 ORDER BY user_id LIMIT $2",
+                &[&after_id, &limit],
+            )
+            .await?;
+        Ok(rows
+            .iter()
+            .map(|row| row.get::<usize, Uuid>(0).to_string())
+            .collect())
+    }
+
+    async fn update_username(&self, user_id: &str, username: &str) -> Result<(), BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let client = &self.client;
+        client
+            .execute(
+                "UPDATE users SET username = $2 WHERE user_id = $1",
+                &[&user_id, &username],
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn insert_message(&self, message: &MessageData) -> Result<MessageData, BaseError> {
+        let id = Uuid::parse_str(&message.id)?;
+        let conversation_id = Uuid::parse_str(&message.conversation_id)?;
+        let author_id = Uuid::parse_str(&message.author.id)?;
+        let created_at = NaiveDateTime::parse_from_str(&message.created_at, MESSAGE_DATE_FORMAT)
+            .map_err(|_| BaseError::new(400, "invalid message created_at"))?;
+
+        let client = &self.client;
+        let row = client
+            .query_one(
+                &format!(
+                    "INSERT INTO messages ({MESSAGE_COLUMNS}) \
+                     VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10) \
+                     RETURNING {MESSAGE_COLUMNS}"
+                ),
+                &[
+                    &id,
+                    &conversation_id,
+                    &author_id,
+                    &message.author.username,
+                    &message.author.email,
+                    &message.author.avatar,
+                    &message.content,
+                    &message.content_type.to_string(),
+                    &message.status.to_string(),
+                    &created_at,
+                ],
+            )
+            .await?;
+
+        Ok(message_from_row(&row))
+    }
+
+    async fn get_messages_by_conversation_id(
+        &self,
+        conversation_id: &str,
+    ) -> Result<Vec<MessageData>, BaseError> {
+        let conversation_id = Uuid::parse_str(conversation_id)?;
+        let client = &self.client;
+        let rows = client
+            .query(
+                &format!(
+                    "SELECT {MESSAGE_COLUMNS} FROM messages \
+                     WHERE conversation_id = $1 ORDER BY seq"
+                ),
+                &[&conversation_id],
+            )
+            .await?;
+
+        Ok(rows.iter().map(message_from_row).collect())
+    }
+
+    async fn get_message_by_id(&self, id: &str) -> Result<MessageData, BaseError> {
+        let id = Uuid::parse_str(id)?;
+        let client = &self.client;
+        let row = client
+            .query_opt(
+                &format!("SELECT {MESSAGE_COLUMNS} FROM messages WHERE id = $1"),
+                &[&id],
+            )
+            .await?;
+
+        match row {
+            Some(row) => Ok(message_from_row(&row)),
+            None => Err(BaseError {
+                code: 404,
+                messages: "message not found".to_string(),
+            }),
+        }
+    }
+
+    async fn get_messages_before(
+        &self,
+        conversation_id: &str,
+        viewer_id: &str,
+        before_id: Option<&str>,
+        limit: i64,
+    ) -> Result<Vec<MessageData>, BaseError> {
+        let conversation_id = Uuid::parse_str(conversation_id)?;
+        let viewer_id = Uuid::parse_str(viewer_id)?;
+        let client = &self.client;
+        // filtered before the limit so a page is never short of messages
+        let not_blocked = "NOT EXISTS (SELECT 1 FROM user_blocks b \
+             WHERE b.user_id = $2 AND b.blocked_id = m.author_id AND b.kind = 'block')";
+        let receipt = "LEFT JOIN message_receipts r ON r.message_id = m.id AND r.user_id = $2";
+        // newest first, the cursor is the oldest message of the previous page
+        let rows = match before_id {
+            Some(before_id) => {
+                let before_id = Uuid::parse_str(before_id)?;
+                client
+                    .query(
+                        &format!(
+                            "SELECT {VIEWER_MESSAGE_COLUMNS} FROM messages m {receipt} \
+                             WHERE m.conversation_id = $1 AND {not_blocked} \
+                             AND m.seq < (SELECT seq FROM messages WHERE id = $3) \
+                             ORDER BY m.seq DESC LIMIT $4"
+                        ),
+                        &[&conversation_id, &viewer_id, &before_id, &limit],
+                    )
+                    .await?
+            }
+            None => {
+                client
+                    .query(
+                        &format!(
+                            "SELECT {VIEWER_MESSAGE_COLUMNS} FROM messages m {receipt} \
+                             WHERE m.conversation_id = $1 AND {not_blocked} \
+                             ORDER BY m.seq DESC LIMIT $3"
+                        ),
+                        &[&conversation_id, &viewer_id, &limit],
+                    )
+                    .await?
+            }
+        };
+
+        Ok(rows.iter().map(message_from_row).collect())
+    }
+
+    async fn update_message_receipt(
+        &self,
+        id: &str,
+        user_id: &str,
+        status: &MessageStatus,
+    ) -> Result<Option<MessageData>, BaseError> {
+        if *status == MessageStatus::Sent {
+            return Ok(None);
+        }
+        let id = Uuid::parse_str(id)?;
+        let user_id = Uuid::parse_str(user_id)?;
+        let client = &self.client;
+        // receipts only move forward: delivered -> read
+        client
+            .execute(
+                "INSERT INTO message_receipts (message_id, user_id, status) VALUES ($1, $2, $3) \
+                 ON CONFLICT (message_id, user_id) DO UPDATE \
+                 SET status = EXCLUDED.status, updated_at = now() \
+                 WHERE message_receipts.status = 'delivered' AND EXCLUDED.status = 'read'",
+                &[&id, &user_id, &status.to_string()],
+            )
+            .await?;
+
+        // the author sees a message read once every other member read it, counted
+        // after the receipt was stored so the last of concurrent receipts sees them all
+        let row = client
+            .query_opt(
+                &format!(
+                    "WITH recipients AS ( \
+                         SELECT r.status FROM messages m \
+                         JOIN conversation_members mb ON mb.conversation_id = m.conversation_id \
+                         AND mb.user_id <> m.author_id \
+                         LEFT JOIN message_receipts r ON r.message_id = m.id AND r.user_id = mb.user_id \
+                         WHERE m.id = $1 \
+                     ), summary AS ( \
+                         SELECT CASE WHEN bool_and(status IS NOT DISTINCT FROM 'read') THEN 'read' \
+                         WHEN bool_and(status IS NOT NULL) THEN 'delivered' ELSE 'sent' END AS next_status \
+                         FROM recipients \
+                     ) \
+                     UPDATE messages SET status = summary.next_status FROM summary \
+                     WHERE id = $1 AND summary.next_status <> messages.status \
+                     AND (messages.status = 'sent' OR summary.next_status = 'read') \
+                     RETURNING {MESSAGE_COLUMNS}"
+                ),
+                &[&id],
+            )
+            .await?;
+
+        Ok(row.as_ref().map(message_from_row))
+    }
+
+    async fn count_unread_messages(
+        &self,
+        conversation_id: &str,
+        user_id: &str,
+    ) -> Result<i64, BaseError> {
+        let conversation_id = Uuid::parse_str(conversation_id)?;
+        let user_id = Uuid::parse_str(user_id)?;
+        let client = &self.client;
+        let row = client
+            .query_one(
+                "SELECT count(*) FROM messages m \
+                 WHERE m.conversation_id = $1 AND m.author_id <> $2 \
+                 AND NOT EXISTS (SELECT 1 FROM message_receipts r \
+                 WHERE r.message_id = m.id AND r.user_id = $2 AND r.status = 'read')",
+                &[&conversation_id, &user_id],
+            )
+            .await?;
+        Ok(row.get(0))
+    }
+
+    async fn get_author_by_user_id(&self, user_id: &str) -> Result<Author, BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let client = &self.client;
+        let row = client
+            .query_opt(
+                "SELECT user_id, first_name, last_name, email FROM users WHERE user_id = $1",
+                &[&user_id],
+            )
+            .await?;
+
+        let row = match row {
+            Some(row) => row,
+            None => {
+                return Err(BaseError {
+                    code: 404,
+                    messages: "user not found".to_string(),
+                })
+            }
+        };
+
+        let user_id = row.get::<usize, Uuid>(0).to_string();
+        let first_name: Option<String> = row.get(1);
+        let last_name: Option<String> = row.get(2);
+        let email: Option<String> = row.get(3);
+        let username = format!(
+            "{} {}",
+            first_name.unwrap_or_default(),
+            last_name.unwrap_or_default()
+        );
+        let avatar = format!("https://api.multiavatar.com/{}.svg", user_id);
+        Ok(Author::new(
+            user_id,
+            username.trim().to_string(),
+            email.unwrap_or_default(),
+            avatar,
+        ))
+    }
+
+    async fn create_conversation(
+        &self,
+        id: &str,
+        kind: &ConversationKind,
+        name: Option<&str>,
+        created_by: &str,
+        member_ids: &[String],
+    ) -> Result<ConversationItem, BaseError> {
+        let conversation_id = Uuid::parse_str(id)?;
+        let created_by = Uuid::parse_str(created_by)?;
+        let member_ids = member_ids
+            .iter()
+            .map(|member_id| Uuid::parse_str(member_id))
+            .collect::<Result<Vec<Uuid>, uuid::Error>>()?;
+
+        // conversation and members are written in one statement, an existing
+        // conversation with the same id is left untouched
+        let client = &self.client;
+        client
+            .execute(
+                "WITH conversation AS ( \
+                     INSERT INTO conversations (id, kind, name, created_by) \
+                     VALUES ($1, $2, $3, $4) ON CONFLICT (id) DO NOTHING RETURNING id \
+                 ) \
+                 INSERT INTO conversation_members (conversation_id, user_id) \
+                 SELECT conversation.id, member_id FROM conversation, unnest($5::uuid[]) AS member_id",
+                &[
+                    &conversation_id,
+                    &kind.to_string(),
+                    &name,
+                    &created_by,
+                    &member_ids,
+                ],
+            )
+            .await?;
+
+        self.get_conversation_by_id(id).await
+    }
+
+    async fn get_conversation_by_id(&self, id: &str) -> Result<ConversationItem, BaseError> {
+        let id = Uuid::parse_str(id)?;
+        let client = &self.client;
+        let row = client
+            .query_opt(
+                "SELECT id, kind, name, created_by, created_at FROM conversations WHERE id = $1",
+                &[&id],
+            )
+            .await?;
+
+        match row {
+            Some(row) => Ok(conversation_from_row(&row)),
+            None => Err(BaseError {
+                code: 404,
+                messages: "conversation not found".to_string(),
+            }),
+        }
+    }
+
+    async fn get_conversations_by_user_id(
+        &self,
+        user_id: &str,
+    ) -> Result<Vec<ConversationItem>, BaseError> {
+        let user_id = Uuid::parse_str(user_id)?;
+        let client = &self.client;
+        let rows = client
+            .query(
+                "SELECT c.id, c.kind, c.name, c.created_by, c.created_at FROM conversations c \
+                 JOIN conversation_members m ON m.conversation_id = c.id \
+                 WHERE m.user_id = $1 ORDER BY c.created_at DESC",
+                &[&user_id],
+            )
+            .await?;
+
+        Ok(rows.iter().map(conversation_from_row).collect())
+    }
+
+    async fn get_conversation_member_ids(
+        &self,
+        conversation_id: &str,
+    ) -> Result<Vec<String>, BaseError> {
+        let conversation_id = Uuid::parse_str(conversation_id)?;
+        let client = &self.client;
+        let rows = client
+            .query(
+                "SELECT user_id FROM conversation_members WHERE conversation_id = $1",
+                &[&conversation_id],
+            )
+            .await?;
+
+        Ok(rows
+            .iter()
+            .map(|row| row.get::<usize, Uuid>(0).to_string())
+            .collect())
+    }
+}
+
+#[async_trait]
+impl NonceStore for dyn DB + Send + Sync {
+    async fn consume_nonce(&self, nonce: &str, expires_at: i64) -> Result<bool, BaseError> {
+        let expires_at = DateTime::from_timestamp(expires_at, 0)
+            .ok_or(BaseError::new(400, "invalid expiry"))?
+            .naive_utc();
+        self.consume_token_nonce(nonce, expires_at).await
+    }
 }
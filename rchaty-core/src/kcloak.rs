@@ -1,11 +1,16 @@
 use std::{fmt::Display, sync::Arc};
 
 use async_trait::async_trait;
+use chrono::Utc;
 use keycloak::{types::UserRepresentation, KeycloakAdmin, KeycloakAdminToken};
+use uuid::Uuid;
 
 use crate::{
     configuration::CoreConfiguration,
-    util::{hmac::HmacSignatureImpl, signature::Signature},
+    util::{
+        hmac::{HmacSignatureImpl, SigningKey},
+        signature::Signature,
+    },
     BaseError, SignupParams,
 };
 
@@ -16,6 +21,9 @@ pub struct KcloakConfig {
     pub password: String,
     pub client_id: String,
     send_email_verification_redirect_uri: String,
+    reset_password_redirect_uri: String,
+    signing_key: SigningKey,
+    signing_previous_keys: Vec<SigningKey>,
 }
 impl From<Arc<CoreConfiguration>> for KcloakConfig {
     fn from(value: Arc<CoreConfiguration>) -> Self {
@@ -28,6 +36,9 @@ impl From<Arc<CoreConfiguration>> for KcloakConfig {
             send_email_verification_redirect_uri: value
                 .app_redircet_send_verify_email_url
                 .to_string(),
+            reset_password_redirect_uri: value.app_redirect_reset_password_url.to_string(),
+            signing_key: value.signing_key.clone(),
+            signing_previous_keys: value.signing_previous_keys.clone(),
         }
     }
 }
@@ -53,6 +64,11 @@ pub trait Kcloak {
     fn get_kconfig(&self) -> &KcloakConfig;
     async fn send_email_verification(&self, user_id: &str) -> Result<(), BaseError>;
     async fn add_user(&self, params: SignupParams) -> Result<UserRepresentation, BaseError>;
+    async fn get_user_id_by_email(&self, email: &str) -> Result<Option<String>, BaseError>;
+    async fn get_username(&self, user_id: &str) -> Result<Option<String>, BaseError>;
+    async fn send_reset_password(&self, user_id: &str) -> Result<(), BaseError>;
+    async fn sign(&self, data: &str) -> Result<String, BaseError>;
+    fn signature(&self) -> HmacSignatureImpl;
     async fn verify_signature(&self, data: &str, signature: &str) -> Result<(), BaseError>;
 }
 
@@ -81,12 +97,14 @@ impl Kcloak for KcloakImpl {
         let admin = self.get_admin().await?;
         let client_id = &self.kconfig.client_id;
         let redirect_uri = self.kconfig.send_email_verification_redirect_uri.to_owned();
-        let password = &self.kconfig.password;
-        let code = HmacSignatureImpl::new(password.to_owned().to_string()).sign(user_id)?;
+        let code = self.signature().sign_expiring(
+            &verify_email_payload(user_id),
+            Utc::now().timestamp(),
+            &Uuid::new_v4().to_string(),
+        )?;
         let redirect_uri = format!("{}?token={}&user_id={}", redirect_uri, code, user_id);
         let redirect_uri = Some(redirect_uri);
 
-        tracing::info!("redirect_uri: {:?}", redirect_uri);
         admin
             .realm_users_with_id_send_verify_email_put(
                 &self.kconfig.realm,
@@ -144,20 +162,103 @@ impl Kcloak for KcloakImpl {
                 None,
             )
             .await?
-            .get(0)
+            .first()
             .unwrap()
             .to_owned();
 
         Ok(user)
     }
 
+    async fn get_user_id_by_email(&self, email: &str) -> Result<Option<String>, BaseError> {
+        let client = self.get_admin().await?;
+        let users = client
+            .realm_users_get(
+                &self.get_kconfig().realm,
+                Some(true),
+                Some(email.to_string()),
+                None,
+                Some(true),
+                Some(true),
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(1),
+                None,
+                None,
+                None,
+            )
+            .await?;
+        Ok(users.first().and_then(|user| user.id.clone()))
+    }
+
+    async fn get_username(&self, user_id: &str) -> Result<Option<String>, BaseError> {
+        let client = self.get_admin().await?;
+        let user = client
+            .realm_users_with_id_get(&self.get_kconfig().realm, user_id)
+            .await?;
+        Ok(user.username)
+    }
+
+    async fn send_reset_password(&self, user_id: &str) -> Result<(), BaseError> {
+        let admin = self.get_admin().await?;
+        let client_id = &self.kconfig.client_id;
+        let code = self.signature().sign_expiring(
+            &reset_password_payload(user_id),
+            Utc::now().timestamp(),
+            &Uuid::new_v4().to_string(),
+        )?;
+        let redirect_uri = format!(
+            "{}?token={}&user_id={}",
+            self.kconfig.reset_password_redirect_uri, code, user_id
+        );
+
+        admin
+            .realm_users_with_id_execute_actions_email_put(
+                &self.kconfig.realm,
+                user_id,
+                Some(client_id.to_string()),
+                Some(RESET_PASSWORD_LIFESPAN as i32),
+                Some(redirect_uri),
+                vec!["UPDATE_PASSWORD".to_string()],
+            )
+            .await?;
+        Ok(())
+    }
+
+    fn signature(&self) -> HmacSignatureImpl {
+        HmacSignatureImpl::with_rotation(
+            self.kconfig.signing_key.clone(),
+            self.kconfig.signing_previous_keys.clone(),
+        )
+    }
+
+    async fn sign(&self, data: &str) -> Result<String, BaseError> {
+        self.signature().sign(data)
+    }
+
     async fn verify_signature(&self, data: &str, signature: &str) -> Result<(), BaseError> {
-        let password = &self.kconfig.password;
-        tracing::info!("data: {}, signature: {}", data, signature);
-        HmacSignatureImpl::new(password.to_owned().to_string()).verify(data, signature)
+        tracing::debug!("verifying signature of: {}", data);
+        self.signature().verify(data, signature)
     }
 }
 
+/// Seconds the reset password email link stays valid.
+pub const RESET_PASSWORD_LIFESPAN: i64 = 60 * 60;
+
+/// Seconds the verify email link stays valid.
+pub const VERIFY_EMAIL_MAX_AGE: i64 = 24 * 60 * 60;
+
+pub fn verify_email_payload(user_id: &str) -> String {
+    format!("verify-email:{}", user_id)
+}
+
+/// Kept apart from the verify email payload so one link cannot stand in for the other.
+pub fn reset_password_payload(user_id: &str) -> String {
+    format!("reset-password:{}", user_id)
+}
+
 impl KcloakImpl {
     pub async fn new(kconfig: KcloakConfig) -> Result<KcloakImpl, Box<dyn std::error::Error>> {
         Ok(KcloakImpl {
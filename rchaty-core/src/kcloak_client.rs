@@ -1,9 +1,20 @@
 use async_trait::async_trait;
-use std::sync::Arc;
+use chrono::Utc;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
 
 use crate::{
-    configuration::CoreConfiguration,
+    configuration::{CoreConfiguration, SessionCookieConfig},
     model::{KcloakErrorResponse, SigninParams, Token, TokenIntrospect, UserInfo},
+    token_verifier::{TokenVerifier, TokenVerifierConfig},
+    util::pkce::AuthorizationFlow,
     BaseError,
 };
 
@@ -12,7 +23,13 @@ pub struct KcloakClientConfig {
     pub client_id: String,
     client_secret: String,
     pub url: String,
+    pub public_url: String,
+    pub redirect_uri: String,
     realm: String,
+    verifier: TokenVerifierConfig,
+    introspection_fallback: bool,
+    introspection_cache_ttl: Duration,
+    pub session_cookie: SessionCookieConfig,
 }
 
 impl From<Arc<CoreConfiguration>> for KcloakClientConfig {
@@ -21,7 +38,13 @@ impl From<Arc<CoreConfiguration>> for KcloakClientConfig {
             client_id: config.keycloak_client_id.to_string(),
             client_secret: config.keycloak_client_secret.to_string(),
             url: config.keycloak_url.to_string(),
+            public_url: config.keycloak_public_url.to_string(),
+            redirect_uri: config.app_oidc_redirect_url.to_string(),
             realm: config.keycloak_realm.to_string(),
+            introspection_fallback: config.token_introspection_fallback,
+            introspection_cache_ttl: Duration::from_secs(config.introspection_cache_ttl),
+            session_cookie: config.session_cookie.clone(),
+            verifier: config.into(),
         }
     }
 }
@@ -29,13 +52,113 @@ impl From<Arc<CoreConfiguration>> for KcloakClientConfig {
 pub struct KcloakClientImpl {
     pub config: Arc<KcloakClientConfig>,
     pub req_client: reqwest::Client,
+    verifier: TokenVerifier,
+    introspect_cache: IntrospectCache,
+}
+
+type TokenHash = [u8; 32];
+
+/// Introspection results keyed by a hash of the token, so tokens never sit in memory.
+struct IntrospectCache {
+    ttl: Duration,
+    entries: Mutex<HashMap<TokenHash, IntrospectEntry>>,
+    /// Orders uses, an `Instant` may repeat for entries touched in a row.
+    clock: AtomicU64,
+}
+
+struct IntrospectEntry {
+    introspect: TokenIntrospect,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+/// Once full, expired entries are purged first, then the least recently used one.
+const INTROSPECT_CACHE_CAPACITY: usize = 1024;
+
+impl IntrospectCache {
+    fn new(ttl: Duration) -> Self {
+        IntrospectCache {
+            ttl,
+            entries: Mutex::new(HashMap::new()),
+            clock: AtomicU64::new(0),
+        }
+    }
+
+    fn key(token: &str) -> TokenHash {
+        Sha256::digest(token.as_bytes()).into()
+    }
+
+    fn get(&self, token: &str) -> Option<TokenIntrospect> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        match entries.get_mut(&Self::key(token)) {
+            Some(entry) if entry.expires_at > now => {
+                entry.last_used = self.clock.fetch_add(1, Ordering::Relaxed);
+                Some(entry.introspect.clone())
+            }
+            _ => None,
+        }
+    }
+
+    fn insert(&self, token: &str, introspect: &TokenIntrospect) {
+        // a rejected token is not asked about again by a legit client, caching it
+        // would only let garbage tokens fill the cache
+        if !introspect.active {
+            return;
+        }
+        // never trust a cached result past the expiry of the token itself
+        let ttl = match introspect.exp {
+            Some(exp) => {
+                let remaining = exp - Utc::now().timestamp();
+                if remaining <= 0 {
+                    return;
+                }
+                self.ttl.min(Duration::from_secs(remaining as u64))
+            }
+            None => self.ttl,
+        };
+        if ttl.is_zero() {
+            return;
+        }
+
+        let now = Instant::now();
+        let key = Self::key(token);
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= INTROSPECT_CACHE_CAPACITY && !entries.contains_key(&key) {
+            entries.retain(|_, entry| entry.expires_at > now);
+            if entries.len() >= INTROSPECT_CACHE_CAPACITY {
+                let lru = entries
+                    .iter()
+                    .min_by_key(|(_, entry)| entry.last_used)
+                    .map(|(key, _)| *key);
+                if let Some(lru) = lru {
+                    entries.remove(&lru);
+                }
+            }
+        }
+        entries.insert(
+            key,
+            IntrospectEntry {
+                introspect: introspect.clone(),
+                expires_at: now + ttl,
+                last_used: self.clock.fetch_add(1, Ordering::Relaxed),
+            },
+        );
+    }
+
+    fn invalidate(&self, token: &str) {
+        self.entries.lock().unwrap().remove(&Self::key(token));
+    }
 }
 
 impl KcloakClientImpl {
     pub fn new(kconfig: KcloakClientConfig) -> Result<KcloakClientImpl, BaseError> {
+        let req_client = reqwest::Client::new();
         Ok(KcloakClientImpl {
+            verifier: TokenVerifier::new(kconfig.verifier.clone(), req_client.clone()),
+            introspect_cache: IntrospectCache::new(kconfig.introspection_cache_ttl),
             config: Arc::new(kconfig),
-            req_client: reqwest::Client::new(),
+            req_client,
         })
     }
 }
@@ -43,10 +166,32 @@ impl KcloakClientImpl {
 #[async_trait]
 pub trait KcloakClient {
     async fn token(&self, request: SigninParams) -> Result<Token, BaseError>;
+    /// Keycloak login page the browser is sent to for the authorization code login.
+    fn authorization_url(&self, flow: &AuthorizationFlow) -> Result<String, BaseError>;
+    async fn exchange_code(&self, code: &str, code_verifier: &str) -> Result<Token, BaseError>;
+    /// Returns the user id (`sub`) of an id token issued for the login that carried `nonce`.
+    async fn verify_id_token(&self, id_token: &str, nonce: &str) -> Result<String, BaseError>;
     async fn introspect(&self, token: &str) -> Result<TokenIntrospect, BaseError>;
+    /// Validates an access token locally, only asking Keycloak when the
+    /// introspection fallback is enabled to catch revoked tokens.
+    async fn verify_token(&self, token: &str) -> Result<TokenIntrospect, BaseError>;
     async fn user_info(&self, token: &str) -> Result<UserInfo, BaseError>;
     async fn revoke_token(&self, token: &str) -> Result<(), BaseError>;
     async fn refresh_token(&self, refresh_token: &str) -> Result<Token, BaseError>;
+    /// Ends the Keycloak session of a refresh token, revoking every token issued in it.
+    async fn logout(&self, refresh_token: &str) -> Result<(), BaseError>;
+    /// Drops the cached introspection of an access token, so it is no longer
+    /// accepted once its session ended.
+    fn forget_token(&self, token: &str);
+
+    /// Returns the user id (`sub`) of an active access token.
+    async fn authenticate(&self, token: &str) -> Result<String, BaseError> {
+        let introspect = self.verify_token(token).await?;
+        match introspect.sub {
+            Some(sub) if introspect.active => Ok(sub),
+            _ => Err(BaseError::new(401, "invalid token")),
+        }
+    }
 }
 
 #[async_trait]
@@ -71,14 +216,73 @@ impl KcloakClient for KcloakClientImpl {
         if resp.status().is_success() {
             return Ok(resp.json::<Token>().await?);
         } else {
+            // keep 4xx, the throttle tells wrong credentials from an outage by the code
+            let code = match resp.status() {
+                status if status.is_client_error() => usize::from(status.as_u16()),
+                _ => 500,
+            };
             let errresp = resp.json::<KcloakErrorResponse>().await?;
             return Err(BaseError {
-                code: 500,
+                code,
+                messages: errresp.error_description,
+            });
+        }
+    }
+
+    fn authorization_url(&self, flow: &AuthorizationFlow) -> Result<String, BaseError> {
+        let url = format!(
+            "{}/realms/{}/protocol/openid-connect/auth",
+            self.config.public_url, self.config.realm
+        );
+        let url = reqwest::Url::parse_with_params(
+            &url,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_uri),
+                ("scope", "openid"),
+                ("state", &flow.state),
+                ("nonce", &flow.nonce),
+                ("code_challenge", &flow.code_challenge()),
+                ("code_challenge_method", "S256"),
+            ],
+        )
+        .map_err(|e| BaseError::new(500, &e.to_string()))?;
+        Ok(url.to_string())
+    }
+
+    async fn exchange_code(&self, code: &str, code_verifier: &str) -> Result<Token, BaseError> {
+        let path = format!(
+            "/realms/{}/protocol/openid-connect/token",
+            self.config.realm
+        );
+        let url = format!("{}{}", self.config.url, path);
+        tracing::debug!("request url: {}", url);
+        // must be the same redirect_uri the code was requested with
+        let params = [
+            ("grant_type", "authorization_code"),
+            ("client_id", &self.config.client_id),
+            ("client_secret", &self.config.client_secret),
+            ("code", code),
+            ("code_verifier", code_verifier),
+            ("redirect_uri", &self.config.redirect_uri),
+        ];
+        let resp = self.req_client.post(url).form(&params).send().await?;
+        if resp.status().is_success() {
+            return Ok(resp.json::<Token>().await?);
+        } else {
+            let errresp = resp.json::<KcloakErrorResponse>().await?;
+            return Err(BaseError {
+                code: 401,
                 messages: errresp.error_description,
             });
         }
     }
 
+    async fn verify_id_token(&self, id_token: &str, nonce: &str) -> Result<String, BaseError> {
+        self.verifier.verify_id_token(id_token, nonce).await
+    }
+
     async fn introspect(&self, token: &str) -> Result<TokenIntrospect, BaseError> {
         let path = format!(
             "/realms/{}/protocol/openid-connect/token/introspect",
@@ -87,6 +291,9 @@ impl KcloakClient for KcloakClientImpl {
         let url = format!("{}{}", self.config.url, path);
         tracing::debug!("request url: {}", url);
         let token = token.replace("Bearer ", "");
+        if let Some(introspect) = self.introspect_cache.get(&token) {
+            return Ok(introspect);
+        }
         let params = [
             ("token", &token),
             ("client_id", &self.config.client_id),
@@ -95,7 +302,9 @@ impl KcloakClient for KcloakClientImpl {
         tracing::debug!("request params: {:?}", params);
         let resp = self.req_client.post(url).form(&params).send().await?;
         if resp.status().is_success() {
-            return Ok(resp.json::<TokenIntrospect>().await?);
+            let introspect = resp.json::<TokenIntrospect>().await?;
+            self.introspect_cache.insert(&token, &introspect);
+            return Ok(introspect);
         } else {
             let errresp = resp.json::<KcloakErrorResponse>().await?;
             return Err(BaseError {
@@ -105,6 +314,18 @@ impl KcloakClient for KcloakClientImpl {
         }
     }
 
+    async fn verify_token(&self, token: &str) -> Result<TokenIntrospect, BaseError> {
+        let token_introspect = self.verifier.verify(token).await?;
+        if !self.config.introspection_fallback {
+            return Ok(token_introspect);
+        }
+        let token_introspect = self.introspect(token).await?;
+        if !token_introspect.active {
+            return Err(BaseError::new(401, "token is no longer active"));
+        }
+        Ok(token_introspect)
+    }
+
     async fn user_info(&self, token: &str) -> Result<UserInfo, BaseError> {
         let path = format!(
             "/realms/{}/protocol/openid-connect/userinfo",
@@ -144,6 +365,8 @@ impl KcloakClient for KcloakClientImpl {
         let resp = self.req_client.post(url).form(&params).send().await?;
 
         if resp.status().is_success() {
+            self.introspect_cache
+                .invalidate(&token.replace("Bearer ", ""));
             return Ok(());
         } else {
             let err = resp.json::<KcloakErrorResponse>().await?;
@@ -165,7 +388,7 @@ impl KcloakClient for KcloakClientImpl {
             ("grant_type", "refresh_token"),
             ("client_id", &self.config.client_id),
             ("client_secret", &self.config.client_secret),
-            ("refresh_token", &refresh_token),
+            ("refresh_token", refresh_token),
         ];
         tracing::debug!("request params: {:?}", params);
         let resp = self.req_client.post(url).form(&params).send().await?;
@@ -179,4 +402,110 @@ impl KcloakClient for KcloakClientImpl {
             });
         }
     }
+
+    async fn logout(&self, refresh_token: &str) -> Result<(), BaseError> {
+        let path = format!(
+            "/realms/{}/protocol/openid-connect/logout",
+            self.config.realm
+        );
+        let url = format!("{}{}", self.config.url, path);
+        tracing::debug!("request url: {}", url);
+        let params = [
+            ("client_id", &self.config.client_id),
+            ("client_secret", &self.config.client_secret),
+            ("refresh_token", &refresh_token.to_string()),
+        ];
+        let resp = self.req_client.post(url).form(&params).send().await?;
+        if resp.status().is_success() {
+            return Ok(());
+        } else {
+            let err = resp.json::<KcloakErrorResponse>().await?;
+            return Err(BaseError {
+                code: 500,
+                messages: err.error_description,
+            });
+        }
+    }
+
+    fn forget_token(&self, token: &str) {
+        self.introspect_cache
+            .invalidate(&token.replace("Bearer ", ""));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn introspect(exp: Option<i64>) -> TokenIntrospect {
+        TokenIntrospect {
+            exp,
+            sub: Some("239f1055-0a6b-4d01-9202-c0fff0a50a26".to_string()),
+            active: true,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_introspect_cache_hit_and_invalidate() {
+        let cache = IntrospectCache::new(Duration::from_secs(30));
+        let exp = Utc::now().timestamp() + 300;
+        cache.insert("token", &introspect(Some(exp)));
+        assert_eq!(cache.get("token"), Some(introspect(Some(exp))));
+        assert_eq!(cache.get("other-token"), None);
+
+        cache.invalidate("token");
+        assert_eq!(cache.get("token"), None);
+    }
+
+    #[test]
+    fn test_introspect_cache_skips_expired_token() {
+        let cache = IntrospectCache::new(Duration::from_secs(30));
+        let exp = Utc::now().timestamp() - 1;
+        cache.insert("token", &introspect(Some(exp)));
+        assert_eq!(cache.get("token"), None);
+    }
+
+    #[test]
+    fn test_introspect_cache_bounded_by_token_expiry() {
+        let cache = IntrospectCache::new(Duration::from_secs(3600));
+        let exp = Utc::now().timestamp() + 60;
+        cache.insert("token", &introspect(Some(exp)));
+
+        let entries = cache.entries.lock().unwrap();
+        let entry = entries.get(&IntrospectCache::key("token")).unwrap();
+        assert!(entry.expires_at <= Instant::now() + Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_introspect_cache_skips_inactive_token() {
+        let cache = IntrospectCache::new(Duration::from_secs(30));
+        let inactive = TokenIntrospect {
+            active: false,
+            ..introspect(Some(Utc::now().timestamp() + 300))
+        };
+        cache.insert("token", &inactive);
+        assert_eq!(cache.get("token"), None);
+    }
+
+    #[test]
+    fn test_introspect_cache_evicts_least_recently_used() {
+        let cache = IntrospectCache::new(Duration::from_secs(30));
+        let exp = Utc::now().timestamp() + 300;
+        for i in 0..INTROSPECT_CACHE_CAPACITY {
+            cache.insert(&format!("token-{}", i), &introspect(Some(exp)));
+        }
+        // touched last, so token-1 is the least recently used one
+        cache.get("token-0");
+        cache.insert("token-new", &introspect(Some(exp)));
+
+        assert_eq!(
+            cache.entries.lock().unwrap().len(),
+            INTROSPECT_CACHE_CAPACITY
+        );
+        assert!(cache.get("token-0").is_some());
+        assert!(cache.get("token-1").is_none());
+        assert!(cache.get("token-new").is_some());
+    }
 }
@@ -6,12 +6,14 @@ pub mod kcloak;
 pub mod kcloak_client;
 pub mod model;
 pub mod service;
+pub mod token_verifier;
 pub mod util;
 
 pub use crate::channel::email_vrf_channel::{
     EmailVerifiedChannel, EmailVerifiedChannelImpl, EmailVerifiedMessage,
 };
 pub use crate::model::BaseError;
+pub use crate::model::ForgotPasswordParams;
 pub use crate::model::SigninParams;
 pub use crate::model::SigninResult;
 pub use crate::model::SignupParams;
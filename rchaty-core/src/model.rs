@@ -23,60 +23,70 @@ impl BaseError {
 impl From<tokio::sync::broadcast::error::SendError<EmailVerifiedMessage>> for BaseError {
     fn from(value: tokio::sync::broadcast::error::SendError<EmailVerifiedMessage>) -> Self {
         tracing::debug!("broadcast error: {:?}", value);
-        return BaseError {
+        BaseError {
             code: 500,
             messages: "broadcast error".to_owned(),
-        };
+        }
     }
 }
 
 impl From<MacError> for BaseError {
     fn from(value: MacError) -> Self {
         tracing::debug!("hmac error: {:?}", value);
-        return BaseError {
+        BaseError {
             code: 400,
             messages: "verified signature error".to_owned(),
-        };
+        }
     }
 }
 
 impl From<DecodeError> for BaseError {
     fn from(value: DecodeError) -> Self {
         tracing::debug!("base64 error: {:?}", value);
-        return BaseError {
+        BaseError {
             code: 500,
             messages: "base64 decode error".to_string(),
-        };
+        }
     }
 }
 
 impl From<uuid::Error> for BaseError {
     fn from(value: uuid::Error) -> Self {
         tracing::debug!("uuid error: {:?}", value);
-        return BaseError {
+        BaseError {
             code: 500,
             messages: value.to_string(),
-        };
+        }
     }
 }
 
 impl From<tokio_postgres::Error> for BaseError {
     fn from(value: tokio_postgres::Error) -> Self {
         tracing::debug!("postgres error: {:?}", value);
-        return BaseError {
+        BaseError {
             code: 500,
             messages: value.to_string(),
-        };
+        }
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for BaseError {
+    fn from(value: jsonwebtoken::errors::Error) -> Self {
+        tracing::debug!("jwt error: {:?}", value);
+        BaseError {
+            code: 401,
+            messages: value.to_string(),
+        }
     }
 }
 
 impl From<reqwest::Error> for BaseError {
     fn from(value: reqwest::Error) -> Self {
         tracing::debug!("reqwest error: {:?}", value);
-        return BaseError {
+        BaseError {
             code: 500,
             messages: value.to_string(),
-        };
+        }
     }
 }
 
@@ -89,25 +99,23 @@ pub struct KcloakErrorResponse {
 impl From<keycloak::KeycloakError> for BaseError {
     fn from(value: keycloak::KeycloakError) -> Self {
         match value {
-            KeycloakError::ReqwestFailure(_) => {
-                return BaseError {
-                    code: 500,
-                    messages: value.to_string(),
-                }
-            }
+            KeycloakError::ReqwestFailure(_) => BaseError {
+                code: 500,
+                messages: value.to_string(),
+            },
             KeycloakError::HttpFailure { status, body, text } => {
                 if let Some(body) = body {
                     let error_message = body.error_message.unwrap_or(text.clone());
                     tracing::error!("{}", error_message);
-                    return BaseError {
+                    BaseError {
                         code: usize::from(status),
                         messages: error_message,
-                    };
+                    }
                 } else {
-                    return BaseError {
+                    BaseError {
                         code: usize::from(status),
                         messages: text,
-                    };
+                    }
                 }
             }
         }
@@ -129,6 +137,20 @@ pub struct SignupParams {
     pub password: String,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ForgotPasswordParams {
+    pub email: String,
+}
+
+/// Who to send a contact request to, by user id or else by email.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ContactRequestParams {
+    #[serde(default)]
+    pub user_id: Option<String>,
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct SigninParams {
     pub username_or_email: String,
@@ -140,6 +162,18 @@ pub struct SigninResult {
     pub token: String,
     pub refresh_token: String,
     pub expires_in: i64,
+    pub refresh_expires_in: i64,
+}
+
+impl From<Token> for SigninResult {
+    fn from(token: Token) -> Self {
+        SigninResult {
+            token: token.access_token,
+            refresh_token: token.refresh_token,
+            expires_in: token.expires_in,
+            refresh_expires_in: token.refresh_expires_in,
+        }
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -147,6 +181,12 @@ pub struct Token {
     pub access_token: String,
     pub refresh_token: String,
     pub expires_in: i64,
+    /// 0 for offline tokens, which do not expire.
+    #[serde(default)]
+    pub refresh_expires_in: i64,
+    /// Only issued when the `openid` scope was granted.
+    #[serde(default)]
+    pub id_token: Option<String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -217,3 +257,18 @@ pub struct VerifiedEmailCallback {
     pub user_id: String,
     pub token: String,
 }
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ResetPasswordCallback {
+    pub user_id: String,
+    pub token: String,
+}
+
+/// Query of the redirect back from the Keycloak login page, either a code or an error.
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AuthorizationCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+    pub error_description: Option<String>,
+}
@@ -1,2 +1,5 @@
 pub mod service_auth;
+pub mod service_chat;
 pub mod service_contact;
+pub mod service_conversation;
+pub mod service_search;
@@ -1,24 +1,43 @@
+use crate::chatchannel::master::MasterChannel;
+use crate::configuration::SessionCookieConfig;
 use crate::db::repository::DB;
+use crate::kcloak::reset_password_payload;
+use crate::kcloak::verify_email_payload;
 use crate::kcloak::Kcloak;
 use crate::kcloak::KcloakImpl;
+use crate::kcloak::RESET_PASSWORD_LIFESPAN;
+use crate::kcloak::VERIFY_EMAIL_MAX_AGE;
 use crate::kcloak_client::KcloakClient;
 use crate::kcloak_client::KcloakClientImpl;
+use crate::model::AuthorizationCallback;
+use crate::token_verifier::unverified_subject;
+use crate::util::pkce::AuthorizationFlow;
+use crate::util::throttle::Throttle;
 use crate::BaseError;
 use crate::EmailVerifiedChannel;
 use crate::EmailVerifiedChannelImpl;
+use crate::ForgotPasswordParams;
 use crate::SigninParams;
 use crate::SigninResult;
 use crate::SignupParams;
 
 use async_trait::async_trait;
+use chrono::Utc;
 use std::sync::Arc;
 
+/// How long a ticket handed out for the email verification socket stays valid, in seconds.
+pub const WS_TICKET_TTL: i64 = 15 * 60;
+
 #[derive(Clone)]
 pub struct AuthImpl {
     kcloak: Arc<dyn Kcloak + Send + Sync>,
     kcloak_client: Arc<dyn KcloakClient + Send + Sync>,
     db: Arc<dyn DB + Send + Sync>,
     email_channel: Arc<dyn EmailVerifiedChannel + Send + Sync>,
+    master_channel: Arc<dyn MasterChannel + Send + Sync>,
+    session_cookie: SessionCookieConfig,
+    throttle: Arc<Throttle>,
+    reset_throttle: Arc<Throttle>,
 }
 
 impl AuthImpl {
@@ -27,30 +46,159 @@ impl AuthImpl {
         kcloak_client: Arc<KcloakClientImpl>,
         db: Arc<dyn DB + Send + Sync>,
         email_channel: EmailVerifiedChannelImpl,
+        master_channel: Arc<dyn MasterChannel + Send + Sync>,
+        throttle: Throttle,
+        reset_throttle: Throttle,
     ) -> Self {
         AuthImpl {
             kcloak: Arc::new(kcloak),
+            session_cookie: kcloak_client.config.session_cookie.clone(),
             kcloak_client,
             db,
             email_channel: Arc::new(email_channel),
+            master_channel,
+            throttle: Arc::new(throttle),
+            reset_throttle: Arc::new(reset_throttle),
+        }
+    }
+
+    /// Runs `attempt` unless the client or the username is throttled, recording the outcome.
+    async fn throttled<T, F>(&self, keys: Vec<String>, attempt: F) -> Result<T, BaseError>
+    where
+        F: std::future::Future<Output = Result<T, BaseError>>,
+    {
+        self.throttle.attempt(&keys)?;
+        let resp = attempt.await;
+        match &resp {
+            Ok(_) => self.throttle.succeeded(&keys),
+            // an outage of Keycloak must not lock users out
+            Err(e) if is_credential_error(e) => self.throttle.failed(&keys),
+            Err(_) => {}
+        }
+        resp
+    }
+
+    /// Fills in the username of users saved before it was stored, so search finds them.
+    pub async fn backfill_usernames(&self) {
+        let mut after_id: Option<String> = None;
+        loop {
+            let user_ids = match self
+                .db
+                .get_user_ids_without_username(after_id.as_deref(), USERNAME_BACKFILL_BATCH)
+                .await
+            {
+                Ok(user_ids) => user_ids,
+                Err(e) => {
+                    tracing::error!("failed to load users without username: {}", e);
+                    return;
+                }
+            };
+            for user_id in user_ids.iter() {
+                // users gone from Keycloak keep no username and are skipped
+                let username = match self.kcloak.get_username(user_id).await {
+                    Ok(Some(username)) => username,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        tracing::warn!("failed to get username of {}: {}", user_id, e);
+                        continue;
+                    }
+                };
+                if let Err(e) = self.db.update_username(user_id, &username).await {
+                    tracing::warn!("failed to backfill username of {}: {}", user_id, e);
+                }
+            }
+            match user_ids.last() {
+                Some(last) => after_id = Some(last.clone()),
+                None => return,
+            }
         }
     }
 }
 
+/// Users looked up in Keycloak per round of the username backfill.
+const USERNAME_BACKFILL_BATCH: i64 = 100;
+
+async fn send_reset_password(kcloak: &(dyn Kcloak + Send + Sync), email: &str) {
+    let user_id = match kcloak.get_user_id_by_email(email).await {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            tracing::info!("reset password requested for unknown email");
+            return;
+        }
+        Err(e) => {
+            tracing::error!("failed to look up user for reset password: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = kcloak.send_reset_password(&user_id).await {
+        tracing::error!("failed to send reset password for {}: {}", user_id, e);
+    }
+}
+
+/// Keycloak answers wrong credentials and rejected sign up data with 400 or 401.
+fn is_credential_error(e: &BaseError) -> bool {
+    matches!(e.code, 400 | 401)
+}
+
+fn throttle_keys(client_ip: &str, usernames: &[&str]) -> Vec<String> {
+    let mut keys = vec![format!("ip:{}", client_ip)];
+    for username in usernames {
+        keys.push(format!("user:{}", username.trim().to_lowercase()));
+    }
+    keys
+}
+
 #[async_trait]
 pub trait Auth {
-    async fn signup(&self, params: SignupParams) -> Result<String, BaseError>;
-    async fn signin(&self, params: SigninParams) -> Result<SigninResult, BaseError>;
+    /// Throttled per `client_ip` and per username, see `Throttle`.
+    async fn signup(&self, params: SignupParams, client_ip: &str) -> Result<String, BaseError>;
+    /// Throttled per `client_ip` and per username, see `Throttle`.
+    async fn signin(
+        &self,
+        params: SigninParams,
+        client_ip: &str,
+    ) -> Result<SigninResult, BaseError>;
+    async fn refresh(&self, refresh_token: &str) -> Result<SigninResult, BaseError>;
+    /// Starts an authorization code login, returns the flow the caller has to keep
+    /// until the callback and the Keycloak login page to send the browser to.
+    fn start_authorization(&self) -> Result<(AuthorizationFlow, String), BaseError>;
+    /// Finishes an authorization code login started with `flow`.
+    async fn finish_authorization(
+        &self,
+        flow: AuthorizationFlow,
+        callback: AuthorizationCallback,
+    ) -> Result<SigninResult, BaseError>;
     async fn send_verify_email(&self, token: &str) -> Result<(), BaseError>;
     async fn revoke_token(&self, token: &str) -> Result<(), BaseError>;
+    /// Ends the Keycloak session and closes the user's sockets, either token may be missing.
+    async fn logout(
+        &self,
+        token: Option<&str>,
+        refresh_token: Option<&str>,
+    ) -> Result<(), BaseError>;
     async fn callback_verify_email(&self, user_id: &str, token: &str) -> Result<(), BaseError>;
+    /// Emails a reset password link, succeeds whether or not the email has an account.
+    /// Throttled per `client_ip` and per email, every request counts as an attempt.
+    async fn forgot_password(
+        &self,
+        params: ForgotPasswordParams,
+        client_ip: &str,
+    ) -> Result<(), BaseError>;
+    async fn callback_reset_password(&self, user_id: &str, token: &str) -> Result<(), BaseError>;
+    /// Returns the user id (`sub`) of an active token.
+    async fn authenticate(&self, token: &str) -> Result<String, BaseError>;
+    /// Signs a short-lived ticket letting a user without a session open a websocket.
+    async fn ws_ticket(&self, user_id: &str) -> Result<String, BaseError>;
+    async fn verify_ws_ticket(&self, user_id: &str, ticket: &str) -> Result<(), BaseError>;
     fn get_email_channel(&self) -> Arc<dyn EmailVerifiedChannel + Send + Sync>;
+    fn get_session_cookie(&self) -> &SessionCookieConfig;
 }
 
 #[async_trait]
 impl Auth for AuthImpl {
-    async fn signup(&self, params: SignupParams) -> Result<String, BaseError> {
-        let user = self.kcloak.add_user(params).await?;
+    async fn signup(&self, params: SignupParams, client_ip: &str) -> Result<String, BaseError> {
+        let keys = throttle_keys(client_ip, &[&params.username, &params.email]);
+        let user = self.throttled(keys, self.kcloak.add_user(params)).await?;
         self.db.save_user(&user).await?;
 
         // send email verification to user after signup
@@ -67,13 +215,66 @@ impl Auth for AuthImpl {
         Ok(user.id.unwrap())
     }
 
-    async fn signin(&self, params: SigninParams) -> Result<SigninResult, BaseError> {
-        let token = self.kcloak_client.token(params).await?;
-        Ok(SigninResult {
-            token: token.access_token,
-            refresh_token: token.refresh_token,
-            expires_in: token.expires_in,
-        })
+    async fn signin(
+        &self,
+        params: SigninParams,
+        client_ip: &str,
+    ) -> Result<SigninResult, BaseError> {
+        let keys = throttle_keys(client_ip, &[&params.username_or_email]);
+        let token = self
+            .throttled(keys, self.kcloak_client.token(params))
+            .await?;
+        Ok(token.into())
+    }
+
+    async fn refresh(&self, refresh_token: &str) -> Result<SigninResult, BaseError> {
+        let token = self.kcloak_client.refresh_token(refresh_token).await?;
+        Ok(token.into())
+    }
+
+    fn start_authorization(&self) -> Result<(AuthorizationFlow, String), BaseError> {
+        let flow = AuthorizationFlow::new();
+        let url = self.kcloak_client.authorization_url(&flow)?;
+        Ok((flow, url))
+    }
+
+    async fn finish_authorization(
+        &self,
+        flow: AuthorizationFlow,
+        callback: AuthorizationCallback,
+    ) -> Result<SigninResult, BaseError> {
+        if let Some(error) = callback.error {
+            tracing::info!(
+                "authorization failed: {} {:?}",
+                error,
+                callback.error_description
+            );
+            return Err(BaseError::new(
+                401,
+                &callback.error_description.unwrap_or(error),
+            ));
+        }
+        // a state we did not hand out means the callback was not started by this browser
+        if callback.state.as_deref() != Some(flow.state.as_str()) {
+            return Err(BaseError::new(400, "login state does not match"));
+        }
+        let code = callback
+            .code
+            .ok_or(BaseError::new(400, "missing authorization code"))?;
+
+        let token = self
+            .kcloak_client
+            .exchange_code(&code, &flow.code_verifier)
+            .await?;
+        let id_token = token
+            .id_token
+            .as_deref()
+            .ok_or(BaseError::new(401, "missing id token"))?;
+        self.kcloak_client
+            .verify_id_token(id_token, &flow.nonce)
+            .await?;
+
+        Ok(token.into())
     }
 
     async fn send_verify_email(&self, token: &str) -> Result<(), BaseError> {
@@ -92,14 +293,207 @@ impl Auth for AuthImpl {
         Ok(self.kcloak_client.revoke_token(token).await?)
     }
 
+    async fn logout(
+        &self,
+        token: Option<&str>,
+        refresh_token: Option<&str>,
+    ) -> Result<(), BaseError> {
+        // resolved before the session ends, the access token may also have expired already
+        let mut user_id = match token {
+            Some(token) => self.authenticate(token).await.ok(),
+            None => None,
+        };
+        let mut resp = Ok(());
+        if let Some(refresh_token) = refresh_token {
+            resp = self.kcloak_client.logout(refresh_token).await;
+            // the subject of a refresh token is only trusted once keycloak accepted it,
+            // anyone can post a forged one naming somebody else
+            if resp.is_ok() {
+                user_id = user_id.or_else(|| unverified_subject(refresh_token));
+            }
+        }
+        if let Some(token) = token {
+            self.kcloak_client.forget_token(token);
+        }
+
+        // the sockets of a verified user go even when keycloak failed, the browser
+        // forgets the session anyway
+        if let Some(user_id) = user_id {
+            self.master_channel.close(&user_id).await?;
+        }
+        resp
+    }
+
     async fn callback_verify_email(&self, user_id: &str, token: &str) -> Result<(), BaseError> {
-        tracing::info!("user_id: {:?}, token: {:?}", user_id, token);
-        self.kcloak.verify_signature(user_id, token).await?;
+        tracing::debug!("verify email callback for user_id: {:?}", user_id);
+        // expired and already used links are rejected before anything is updated
+        self.kcloak
+            .signature()
+            .verify_once(
+                &verify_email_payload(user_id),
+                token,
+                Utc::now().timestamp(),
+                VERIFY_EMAIL_MAX_AGE,
+                self.db.as_ref(),
+            )
+            .await?;
         self.db.update_verified_email(user_id).await?;
         Ok(())
     }
 
+    async fn forgot_password(
+        &self,
+        params: ForgotPasswordParams,
+        client_ip: &str,
+    ) -> Result<(), BaseError> {
+        let email = params.email.trim().to_string();
+        if email.is_empty() {
+            return Err(BaseError::new(400, "email is required"));
+        }
+        // every request sends a mail, so each one counts whatever the outcome
+        self.reset_throttle
+            .attempt(&throttle_keys(client_ip, &[&email]))?;
+
+        // looked up in the background so the response does not tell whether the account exists
+        let kcloak = self.kcloak.clone();
+        tokio::spawn(async move { send_reset_password(kcloak.as_ref(), &email).await });
+        Ok(())
+    }
+
+    async fn callback_reset_password(&self, user_id: &str, token: &str) -> Result<(), BaseError> {
+        // expired and already used links are rejected, as for verify email
+        self.kcloak
+            .signature()
+            .verify_once(
+                &reset_password_payload(user_id),
+                token,
+                Utc::now().timestamp(),
+                RESET_PASSWORD_LIFESPAN,
+                self.db.as_ref(),
+            )
+            .await
+    }
+
+    async fn authenticate(&self, token: &str) -> Result<String, BaseError> {
+        self.kcloak_client.authenticate(token).await
+    }
+
+    async fn ws_ticket(&self, user_id: &str) -> Result<String, BaseError> {
+        let expires_at = Utc::now().timestamp() + WS_TICKET_TTL;
+        let signature = self
+            .kcloak
+            .sign(&format!("ws:{}:{}", user_id, expires_at))
+            .await?;
+        Ok(format!("{}.{}", expires_at, signature))
+    }
+
+    async fn verify_ws_ticket(&self, user_id: &str, ticket: &str) -> Result<(), BaseError> {
+        let invalid = || BaseError::new(401, "invalid ticket");
+        let (expires_at, signature) = ticket.split_once('.').ok_or_else(invalid)?;
+        let expires_at: i64 = expires_at.parse().map_err(|_| invalid())?;
+        if expires_at < Utc::now().timestamp() {
+            return Err(BaseError::new(401, "ticket expired"));
+        }
+        self.kcloak
+            .verify_signature(&format!("ws:{}:{}", user_id, expires_at), signature)
+            .await
+            .map_err(|_| invalid())
+    }
+
     fn get_email_channel(&self) -> Arc<dyn EmailVerifiedChannel + Send + Sync> {
         Arc::clone(&self.email_channel)
     }
+
+    fn get_session_cookie(&self) -> &SessionCookieConfig {
+        &self.session_cookie
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::{sync::Mutex, time::Duration};
+
+    use keycloak::{types::UserRepresentation, KeycloakAdmin};
+
+    use super::*;
+    use crate::{
+        configuration::ThrottleConfig, kcloak::KcloakConfig, util::hmac::HmacSignatureImpl,
+    };
+
+    /// Only knows `john@example.com`, records the users a reset was sent to.
+    #[derive(Default)]
+    struct MockKcloak {
+        reset_sent: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl Kcloak for MockKcloak {
+        async fn get_admin(&self) -> Result<KeycloakAdmin, BaseError> {
+            unimplemented!()
+        }
+        fn get_kconfig(&self) -> &KcloakConfig {
+            unimplemented!()
+        }
+        async fn send_email_verification(&self, _user_id: &str) -> Result<(), BaseError> {
+            unimplemented!()
+        }
+        async fn add_user(&self, _params: SignupParams) -> Result<UserRepresentation, BaseError> {
+            unimplemented!()
+        }
+        async fn get_user_id_by_email(&self, email: &str) -> Result<Option<String>, BaseError> {
+            Ok((email == "john@example.com").then(|| "john".to_string()))
+        }
+        async fn get_username(&self, _user_id: &str) -> Result<Option<String>, BaseError> {
+            unimplemented!()
+        }
+        async fn send_reset_password(&self, user_id: &str) -> Result<(), BaseError> {
+            self.reset_sent.lock().unwrap().push(user_id.to_string());
+            Ok(())
+        }
+        async fn sign(&self, _data: &str) -> Result<String, BaseError> {
+            unimplemented!()
+        }
+        fn signature(&self) -> HmacSignatureImpl {
+            unimplemented!()
+        }
+        async fn verify_signature(&self, _data: &str, _signature: &str) -> Result<(), BaseError> {
+            unimplemented!()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reset_password_only_sent_to_known_email() {
+        let kcloak = MockKcloak::default();
+        send_reset_password(&kcloak, "jane@example.com").await;
+        assert!(kcloak.reset_sent.lock().unwrap().is_empty());
+
+        send_reset_password(&kcloak, "john@example.com").await;
+        assert_eq!(*kcloak.reset_sent.lock().unwrap(), vec!["john".to_string()]);
+    }
+
+    #[test]
+    fn test_reset_password_throttled_per_email_across_ips() {
+        let throttle = Throttle::new(ThrottleConfig {
+            max_attempts: 2,
+            window: Duration::from_secs(60),
+            lockout_threshold: 5,
+            lockout_base: Duration::from_secs(10),
+            lockout_max: Duration::from_secs(60),
+        });
+        throttle
+            .attempt(&throttle_keys("10.0.0.1", &["john@example.com"]))
+            .unwrap();
+        throttle
+            .attempt(&throttle_keys("10.0.0.2", &[" John@Example.com "]))
+            .unwrap();
+        let err = throttle
+            .attempt(&throttle_keys("10.0.0.3", &["john@example.com"]))
+            .unwrap_err();
+        assert_eq!(err.code, 429);
+
+        assert!(throttle
+            .attempt(&throttle_keys("10.0.0.3", &["jane@example.com"]))
+            .is_ok());
+    }
 }
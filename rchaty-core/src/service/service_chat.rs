@@ -0,0 +1,298 @@
+use std::{sync::Arc, time::Instant};
+
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use tokio::task::JoinHandle;
+use uuid::Uuid;
+
+use crate::{
+    chatchannel::{
+        master::{ChannelEvent, MasterChannel},
+        model::{
+            direct_conversation_id, now_timestamp, Author, IncomingMessage, MessageData,
+            MessageStatus, TypingData, MESSAGE_DATE_FORMAT,
+        },
+        presence::Presence,
+        typing::{TypingTracker, TYPING_SWEEP_INTERVAL},
+    },
+    configuration::SessionCookieConfig,
+    db::repository::DB,
+    kcloak_client::{KcloakClient, KcloakClientImpl},
+    service::{service_contact::MESSAGE_PREVIEW_LENGTH, service_conversation::ensure_member},
+    BaseError, SigninResult,
+};
+
+#[async_trait]
+pub trait Chat {
+    async fn send_message(&self, message: MessageData) -> Result<MessageData, BaseError>;
+    async fn send_incoming_message(
+        &self,
+        author_id: &str,
+        incoming: IncomingMessage,
+    ) -> Result<MessageData, BaseError>;
+    async fn update_message_status(
+        &self,
+        user_id: &str,
+        message_id: &str,
+        status: MessageStatus,
+    ) -> Result<(), BaseError>;
+    async fn send_typing(&self, user_id: &str, conversation_id: &str) -> Result<(), BaseError>;
+    /// The author's contact list entry as `user_id` sees it once the message arrived,
+    /// `None` for messages outside of direct conversations.
+    async fn contact_preview(
+        &self,
+        user_id: &str,
+        message: &MessageData,
+    ) -> Result<Option<ContactPreview>, BaseError>;
+    /// Returns the user id (`sub`) of an active token.
+    async fn authenticate(&self, token: &str) -> Result<String, BaseError>;
+    /// Exchanges a refresh token for a new session, for sockets opened after the
+    /// access token expired.
+    async fn refresh(&self, refresh_token: &str) -> Result<SigninResult, BaseError>;
+    fn get_session_cookie(&self) -> &SessionCookieConfig;
+    fn get_master_channel(&self) -> Arc<dyn MasterChannel + Send + Sync>;
+    fn get_presence(&self) -> Arc<dyn Presence + Send + Sync>;
+}
+
+#[derive(Debug, Clone)]
+pub struct ContactPreview {
+    pub friend_id: String,
+    pub last_message: String,
+    pub last_message_at: NaiveDateTime,
+    pub unread_count: i64,
+}
+
+#[derive(Clone)]
+pub struct ChatImpl {
+    db: Arc<dyn DB + Send + Sync>,
+    master_channel: Arc<dyn MasterChannel + Send + Sync>,
+    presence: Arc<dyn Presence + Send + Sync>,
+    kcloak_client: Arc<dyn KcloakClient + Send + Sync>,
+    session_cookie: SessionCookieConfig,
+    typing: TypingTracker,
+}
+
+impl ChatImpl {
+    pub fn new(
+        db: Arc<dyn DB + Send + Sync>,
+        master_channel: Arc<dyn MasterChannel + Send + Sync>,
+        presence: Arc<dyn Presence + Send + Sync>,
+        kcloak_client: Arc<KcloakClientImpl>,
+    ) -> Self {
+        ChatImpl {
+            db,
+            master_channel,
+            presence,
+            session_cookie: kcloak_client.config.session_cookie.clone(),
+            kcloak_client,
+            typing: TypingTracker::new(),
+        }
+    }
+
+    /// Takes down the typing indicators of clients that stopped refreshing them, e.g.
+    /// ones that crashed, for as long as the server runs.
+    pub fn spawn_typing_sweeper(&self) -> JoinHandle<()> {
+        let chat = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TYPING_SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                for entry in chat.typing.sweep(Instant::now()) {
+                    chat.publish_typing(
+                        &entry.member_ids,
+                        &entry.conversation_id,
+                        entry.author,
+                        false,
+                    )
+                    .await;
+                }
+            }
+        })
+    }
+
+    /// Typing indicators only go to the other members and are never stored.
+    async fn publish_typing(
+        &self,
+        member_ids: &[String],
+        conversation_id: &str,
+        author: Author,
+        typing: bool,
+    ) {
+        let author_id = author.id.clone();
+        let restrictions = match self
+            .db
+            .get_restrictions_against(&author_id, member_ids)
+            .await
+        {
+            Ok(restrictions) => restrictions,
+            Err(e) => {
+                tracing::warn!("user: {} failed to load restrictions: {}", author_id, e);
+                return;
+            }
+        };
+        let event = ChannelEvent::Typing(TypingData {
+            conversation_id: conversation_id.to_string(),
+            user_id: author.id,
+            username: author.username,
+            typing,
+        });
+        let member_ids: Vec<String> = member_ids
+            .iter()
+            .filter(|id| **id != author_id)
+            .cloned()
+            .collect();
+        self.master_channel
+            .send_restricted(&member_ids, event, &restrictions)
+            .await;
+    }
+}
+
+#[async_trait]
+impl Chat for ChatImpl {
+    async fn send_message(&self, mut message: MessageData) -> Result<MessageData, BaseError> {
+        // ids and timestamps are always assigned by the server
+        message.id = Uuid::new_v4().to_string();
+        message.created_at = now_timestamp().format(MESSAGE_DATE_FORMAT).to_string();
+
+        // persist first so the message survives even when nobody is listening
+        let message = self.db.insert_message(&message).await?;
+
+        // every member gets a copy, the author included so all open tabs stay in sync,
+        // except for members who blocked the author
+        let member_ids = self
+            .db
+            .get_conversation_member_ids(&message.conversation_id)
+            .await?;
+        let restrictions = self
+            .db
+            .get_restrictions_against(&message.author.id, &member_ids)
+            .await?;
+        let event = ChannelEvent::ChatMessage(message.clone());
+        self.master_channel
+            .send_restricted(&member_ids, event, &restrictions)
+            .await;
+        Ok(message)
+    }
+
+    async fn send_incoming_message(
+        &self,
+        author_id: &str,
+        incoming: IncomingMessage,
+    ) -> Result<MessageData, BaseError> {
+        incoming.validate()?;
+        ensure_member(self.db.as_ref(), &incoming.conversation_id, author_id).await?;
+        let author = self.db.get_author_by_user_id(author_id).await?;
+        let message = incoming.into_message_data(author);
+        let message = self.send_message(message).await?;
+
+        if let Some(entry) = self.typing.clear(&message.conversation_id, author_id) {
+            self.publish_typing(
+                &entry.member_ids,
+                &entry.conversation_id,
+                entry.author,
+                false,
+            )
+            .await;
+        }
+        Ok(message)
+    }
+
+    async fn update_message_status(
+        &self,
+        user_id: &str,
+        message_id: &str,
+        status: MessageStatus,
+    ) -> Result<(), BaseError> {
+        let message = self.db.get_message_by_id(message_id).await?;
+        // only recipients acknowledge a message
+        if message.author.id == user_id {
+            return Ok(());
+        }
+        ensure_member(self.db.as_ref(), &message.conversation_id, user_id).await?;
+
+        let message = match self
+            .db
+            .update_message_receipt(message_id, user_id, &status)
+            .await?
+        {
+            Some(message) => message,
+            None => return Ok(()),
+        };
+
+        // let the author's tabs know so they can update the ticks
+        let author_id = message.author.id.clone();
+        let event = ChannelEvent::MessageStatus(message);
+        if let Err(e) = self.master_channel.send(&author_id, event.into()).await {
+            tracing::warn!("user: {} failed to receive status: {}", author_id, e);
+        }
+        Ok(())
+    }
+
+    async fn send_typing(&self, user_id: &str, conversation_id: &str) -> Result<(), BaseError> {
+        // clients refresh every few seconds while typing, only the first frame is checked
+        if self.typing.touch(conversation_id, user_id, Instant::now()) {
+            return Ok(());
+        }
+        let member_ids = ensure_member(self.db.as_ref(), conversation_id, user_id).await?;
+        let author = self.db.get_author_by_user_id(user_id).await?;
+        let started = self.typing.start(
+            conversation_id,
+            author.clone(),
+            member_ids.clone(),
+            Instant::now(),
+        );
+        if started {
+            self.publish_typing(&member_ids, conversation_id, author, true)
+                .await;
+        }
+        Ok(())
+    }
+
+    async fn contact_preview(
+        &self,
+        user_id: &str,
+        message: &MessageData,
+    ) -> Result<Option<ContactPreview>, BaseError> {
+        if message.conversation_id != direct_conversation_id(user_id, &message.author.id) {
+            return Ok(None);
+        }
+        let unread_count = self
+            .db
+            .count_unread_messages(&message.conversation_id, user_id)
+            .await?;
+        let last_message_at =
+            NaiveDateTime::parse_from_str(&message.created_at, MESSAGE_DATE_FORMAT)
+                .map_err(|_| BaseError::new(500, "invalid message date"))?;
+        Ok(Some(ContactPreview {
+            friend_id: message.author.id.clone(),
+            last_message: message
+                .content
+                .chars()
+                .take(MESSAGE_PREVIEW_LENGTH as usize)
+                .collect(),
+            last_message_at,
+            unread_count,
+        }))
+    }
+
+    async fn authenticate(&self, token: &str) -> Result<String, BaseError> {
+        self.kcloak_client.authenticate(token).await
+    }
+
+    async fn refresh(&self, refresh_token: &str) -> Result<SigninResult, BaseError> {
+        let token = self.kcloak_client.refresh_token(refresh_token).await?;
+        Ok(token.into())
+    }
+
+    fn get_session_cookie(&self) -> &SessionCookieConfig {
+        &self.session_cookie
+    }
+
+    fn get_master_channel(&self) -> Arc<dyn MasterChannel + Send + Sync> {
+        Arc::clone(&self.master_channel)
+    }
+
+    fn get_presence(&self) -> Arc<dyn Presence + Send + Sync> {
+        Arc::clone(&self.presence)
+    }
+}
@@ -2,12 +2,81 @@ use std::sync::Arc;
 
 use async_trait::async_trait;
 use chrono::NaiveDateTime;
+use uuid::Uuid;
 
-use crate::{db::repository::DB, kcloak_client::KcloakClient, model::TokenIntrospect, BaseError};
+use crate::{
+    chatchannel::{
+        master::{ChannelEvent, MasterChannel},
+        model::{ContactRequest, Restriction},
+        presence::Presence,
+    },
+    db::repository::DB,
+    kcloak_client::KcloakClient,
+    model::ContactRequestParams,
+    BaseError,
+};
+
+/// Characters of the last message shown in the contact list.
+pub const MESSAGE_PREVIEW_LENGTH: i32 = 80;
+
+/// Longest nickname a contact can be renamed to.
+pub const MAX_CONTACT_NAME_LENGTH: usize = 64;
 
 #[async_trait]
 pub trait Contact {
     async fn show_contact_list(&self, token: &str) -> Result<Vec<ContactItem>, BaseError>;
+    /// The name the user gave a contact.
+    async fn show_contact_name(&self, token: &str, friend_id: &str) -> Result<String, BaseError>;
+    /// Sets the name the user sees for a contact, the contact does not see it.
+    async fn rename_contact(
+        &self,
+        token: &str,
+        friend_id: &str,
+        name: &str,
+    ) -> Result<(), BaseError>;
+    /// Removes a contact from the user's list only, the other user keeps theirs.
+    async fn remove_contact(&self, token: &str, friend_id: &str) -> Result<(), BaseError>;
+    /// Asks a user, by id or email, to become a contact. When they already asked
+    /// us, their request is accepted instead.
+    ///
+    /// An email nobody can be asked with, unknown or blocked, succeeds with `None`
+    /// so the answer does not tell which emails have an account.
+    async fn send_contact_request(
+        &self,
+        token: &str,
+        params: ContactRequestParams,
+    ) -> Result<Option<ContactRequest>, BaseError>;
+    /// Adds each user to the other's contacts, only the recipient can accept.
+    async fn accept_contact_request(&self, token: &str, request_id: &str) -> Result<(), BaseError>;
+    /// Drops a request without telling the sender, only the recipient can decline.
+    async fn decline_contact_request(&self, token: &str, request_id: &str)
+        -> Result<(), BaseError>;
+    /// Takes a request back, only the sender can cancel.
+    async fn cancel_contact_request(&self, token: &str, request_id: &str) -> Result<(), BaseError>;
+    async fn show_contact_requests(&self, token: &str) -> Result<ContactRequests, BaseError>;
+    /// Blocks or mutes a user, replacing whichever of the two was set before.
+    /// Blocking also drops the pending requests between both users.
+    async fn restrict_user(
+        &self,
+        token: &str,
+        user_id: &str,
+        restriction: Restriction,
+    ) -> Result<(), BaseError>;
+    async fn unrestrict_user(
+        &self,
+        token: &str,
+        user_id: &str,
+        restriction: Restriction,
+    ) -> Result<(), BaseError>;
+    async fn show_restricted_users(&self, token: &str) -> Result<Vec<RestrictedUser>, BaseError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct RestrictedUser {
+    pub user_id: String,
+    pub name: String,
+    pub restriction: Restriction,
+    pub created_at: NaiveDateTime,
 }
 
 #[derive(Debug, Clone)]
@@ -17,30 +86,282 @@ pub struct ContactItem {
     pub friend_id: String,
     pub name: String,
     pub created_at: NaiveDateTime,
+    pub last_seen_at: Option<NaiveDateTime>,
+    pub online: bool,
+    /// Set when the user blocked or muted this contact.
+    pub restriction: Option<Restriction>,
+    /// Start of the latest message of the direct conversation, whoever wrote it.
+    pub last_message: Option<String>,
+    pub last_message_at: Option<NaiveDateTime>,
+    /// Messages of the contact the user has not read yet.
+    pub unread_count: i64,
+}
+
+/// Pending requests of a user, both directions, newest first.
+#[derive(Debug, Clone, Default)]
+pub struct ContactRequests {
+    pub incoming: Vec<ContactRequest>,
+    pub outgoing: Vec<ContactRequest>,
 }
 
 pub struct ContactImpl {
     db: Arc<dyn DB + Send + Sync>,
     kcloak_client: Arc<dyn KcloakClient + Send + Sync>,
+    presence: Arc<dyn Presence + Send + Sync>,
+    master_channel: Arc<dyn MasterChannel + Send + Sync>,
 }
 
 impl ContactImpl {
     pub fn new(
         db: Arc<dyn DB + Send + Sync>,
         kcloak_client: Arc<dyn KcloakClient + Send + Sync>,
+        presence: Arc<dyn Presence + Send + Sync>,
+        master_channel: Arc<dyn MasterChannel + Send + Sync>,
     ) -> Self {
-        ContactImpl { db, kcloak_client }
+        ContactImpl {
+            db,
+            kcloak_client,
+            presence,
+            master_channel,
+        }
+    }
+
+    /// The recipient and whether they were given by email, `None` for an unknown email.
+    async fn recipient_id(
+        &self,
+        params: ContactRequestParams,
+    ) -> Result<(Option<String>, bool), BaseError> {
+        match (params.user_id, params.email) {
+            (Some(user_id), _) if !user_id.trim().is_empty() => {
+                let recipient_id = Uuid::parse_str(user_id.trim())?.to_string();
+                self.db
+                    .get_author_by_user_id(&recipient_id)
+                    .await
+                    .map_err(|_| BaseError::new(404, "user not found"))?;
+                Ok((Some(recipient_id), false))
+            }
+            (_, Some(email)) if !email.trim().is_empty() => {
+                let recipient_id = self.db.get_user_id_by_email(email.trim()).await?;
+                Ok((recipient_id, true))
+            }
+            _ => Err(BaseError::new(400, "user id or email is required")),
+        }
+    }
+
+    /// The request, provided `user_id` is the side of it that may act on it.
+    async fn pending_request(
+        &self,
+        request_id: &str,
+        user_id: &str,
+        as_recipient: bool,
+    ) -> Result<ContactRequest, BaseError> {
+        let request = self
+            .db
+            .get_contact_request(request_id)
+            .await?
+            .ok_or(BaseError::new(404, "contact request not found"))?;
+        let party_id = if as_recipient {
+            &request.recipient_id
+        } else {
+            &request.sender_id
+        };
+        if party_id != user_id {
+            return Err(BaseError::new(404, "contact request not found"));
+        }
+        Ok(request)
+    }
+
+    async fn accept(&self, request: ContactRequest) -> Result<(), BaseError> {
+        self.db.accept_contact_request(&request.id).await?;
+        self.notify(
+            &request.sender_id,
+            ChannelEvent::ContactRequestAccepted(request.clone()),
+        )
+        .await;
+        Ok(())
+    }
+
+    /// Notifications are best effort, the request itself is already stored.
+    async fn notify(&self, user_id: &str, event: ChannelEvent) {
+        let channel_type = event.channel_type();
+        if let Err(e) = self.master_channel.send(user_id, event.into()).await {
+            tracing::warn!(
+                "user: {} failed to receive {}: {}",
+                user_id,
+                channel_type,
+                e
+            );
+        }
     }
 }
 
 #[async_trait]
 impl Contact for ContactImpl {
     async fn show_contact_list(&self, token: &str) -> Result<Vec<ContactItem>, BaseError> {
-        let token_introspect: TokenIntrospect = self.kcloak_client.introspect(token).await?;
-        let user_id = token_introspect
-            .sub
-            .ok_or(BaseError::new(401, "invalid token"))?;
-        let contacts = self.db.get_contacts_by_user_id(&user_id).await?;
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        let mut contacts = self.db.get_contacts_by_user_id(&user_id).await?;
+        let friend_ids: Vec<String> = contacts
+            .iter()
+            .map(|contact| contact.friend_id.clone())
+            .collect();
+        let online = self.presence.online_user_ids(&friend_ids).await?;
+        for contact in contacts.iter_mut() {
+            contact.online = online.contains(&contact.friend_id);
+        }
         Ok(contacts)
     }
+
+    async fn show_contact_name(&self, token: &str, friend_id: &str) -> Result<String, BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        self.db
+            .get_contact_name(&user_id, friend_id)
+            .await?
+            .ok_or_else(|| BaseError::new(404, "contact not found"))
+    }
+
+    async fn rename_contact(
+        &self,
+        token: &str,
+        friend_id: &str,
+        name: &str,
+    ) -> Result<(), BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(BaseError::new(400, "name is required"));
+        }
+        if name.chars().count() > MAX_CONTACT_NAME_LENGTH {
+            return Err(BaseError::new(
+                400,
+                &format!("name is longer than {} characters", MAX_CONTACT_NAME_LENGTH),
+            ));
+        }
+        self.db.update_contact_name(&user_id, friend_id, name).await
+    }
+
+    async fn remove_contact(&self, token: &str, friend_id: &str) -> Result<(), BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        self.db.delete_contact(&user_id, friend_id).await
+    }
+
+    async fn send_contact_request(
+        &self,
+        token: &str,
+        params: ContactRequestParams,
+    ) -> Result<Option<ContactRequest>, BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        let (recipient_id, by_email) = self.recipient_id(params).await?;
+        let Some(recipient_id) = recipient_id else {
+            return Ok(None);
+        };
+        if recipient_id == user_id {
+            return Err(BaseError::new(400, "you cannot add yourself"));
+        }
+        // answered like an unknown user so a block is not given away
+        if self.db.is_blocked(&user_id, &recipient_id).await? {
+            if by_email {
+                return Ok(None);
+            }
+            return Err(BaseError::new(404, "user not found"));
+        }
+        if self.db.is_contact(&user_id, &recipient_id).await? {
+            return Err(BaseError::new(409, "already in your contacts"));
+        }
+
+        // both asked each other, that is as good as accepting
+        if let Some(request) = self
+            .db
+            .find_contact_request(&recipient_id, &user_id)
+            .await?
+        {
+            self.accept(request.clone()).await?;
+            return Ok(Some(request));
+        }
+
+        let id = Uuid::new_v4().to_string();
+        let request = self
+            .db
+            .insert_contact_request(&id, &user_id, &recipient_id)
+            .await?
+            .ok_or(BaseError::new(409, "contact request already sent"))?;
+        self.notify(&recipient_id, ChannelEvent::ContactRequest(request.clone()))
+            .await;
+        Ok(Some(request))
+    }
+
+    async fn accept_contact_request(&self, token: &str, request_id: &str) -> Result<(), BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        let request = self.pending_request(request_id, &user_id, true).await?;
+        self.accept(request).await
+    }
+
+    async fn decline_contact_request(
+        &self,
+        token: &str,
+        request_id: &str,
+    ) -> Result<(), BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        let request = self.pending_request(request_id, &user_id, true).await?;
+        self.db.delete_contact_request(&request.id).await
+    }
+
+    async fn cancel_contact_request(&self, token: &str, request_id: &str) -> Result<(), BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        let request = self.pending_request(request_id, &user_id, false).await?;
+        self.db.delete_contact_request(&request.id).await?;
+        self.notify(
+            &request.recipient_id,
+            ChannelEvent::ContactRequestCancelled(request.clone()),
+        )
+        .await;
+        Ok(())
+    }
+
+    async fn show_contact_requests(&self, token: &str) -> Result<ContactRequests, BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        Ok(ContactRequests {
+            incoming: self.db.get_incoming_contact_requests(&user_id).await?,
+            outgoing: self.db.get_outgoing_contact_requests(&user_id).await?,
+        })
+    }
+
+    async fn restrict_user(
+        &self,
+        token: &str,
+        user_id: &str,
+        restriction: Restriction,
+    ) -> Result<(), BaseError> {
+        let owner_id = self.kcloak_client.authenticate(token).await?;
+        let user_id = Uuid::parse_str(user_id)?.to_string();
+        if user_id == owner_id {
+            return Err(BaseError::new(400, "you cannot restrict yourself"));
+        }
+        self.db.get_author_by_user_id(&user_id).await?;
+        self.db
+            .set_restriction(&owner_id, &user_id, restriction)
+            .await?;
+        if restriction == Restriction::Block {
+            self.db
+                .delete_contact_requests_between(&owner_id, &user_id)
+                .await?;
+        }
+        Ok(())
+    }
+
+    async fn unrestrict_user(
+        &self,
+        token: &str,
+        user_id: &str,
+        restriction: Restriction,
+    ) -> Result<(), BaseError> {
+        let owner_id = self.kcloak_client.authenticate(token).await?;
+        self.db
+            .remove_restriction(&owner_id, user_id, restriction)
+            .await
+    }
+
+    async fn show_restricted_users(&self, token: &str) -> Result<Vec<RestrictedUser>, BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        self.db.get_restricted_users(&user_id).await
+    }
 }
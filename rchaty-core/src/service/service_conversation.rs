@@ -0,0 +1,226 @@
+use std::{fmt::Display, sync::Arc};
+
+use async_trait::async_trait;
+use chrono::NaiveDateTime;
+use uuid::Uuid;
+
+use crate::{
+    chatchannel::model::{direct_conversation_id, MessageData},
+    db::repository::DB,
+    kcloak_client::KcloakClient,
+    BaseError,
+};
+
+/// Maximum number of members, creator included, in a group conversation.
+pub const MAX_GROUP_MEMBERS: usize = 256;
+
+/// Number of messages returned per history page.
+pub const MESSAGE_PAGE_SIZE: usize = 30;
+
+#[async_trait]
+pub trait Conversation {
+    async fn open_direct_conversation(
+        &self,
+        token: &str,
+        friend_id: &str,
+    ) -> Result<ConversationItem, BaseError>;
+    async fn open_conversation(
+        &self,
+        token: &str,
+        conversation_id: &str,
+    ) -> Result<ConversationItem, BaseError>;
+    async fn create_group_conversation(
+        &self,
+        token: &str,
+        name: &str,
+        member_ids: Vec<String>,
+    ) -> Result<ConversationItem, BaseError>;
+    async fn show_conversation_list(&self, token: &str)
+        -> Result<Vec<ConversationItem>, BaseError>;
+    async fn show_messages(
+        &self,
+        token: &str,
+        conversation_id: &str,
+        before_id: Option<&str>,
+    ) -> Result<MessagePage, BaseError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConversationKind {
+    Direct,
+    Group,
+}
+
+impl Display for ConversationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConversationKind::Direct => write!(f, "direct"),
+            ConversationKind::Group => write!(f, "group"),
+        }
+    }
+}
+
+impl ConversationKind {
+    pub fn from_string(kind: &str) -> Self {
+        match kind {
+            "group" => ConversationKind::Group,
+            _ => ConversationKind::Direct,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ConversationItem {
+    pub id: String,
+    pub kind: ConversationKind,
+    pub name: Option<String>,
+    pub created_by: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// A page of messages in chronological order, `next_cursor` points to older messages if any.
+#[derive(Debug, Clone)]
+pub struct MessagePage {
+    pub user_id: String,
+    pub messages: Vec<MessageData>,
+    pub next_cursor: Option<String>,
+}
+
+pub struct ConversationImpl {
+    db: Arc<dyn DB + Send + Sync>,
+    kcloak_client: Arc<dyn KcloakClient + Send + Sync>,
+}
+
+impl ConversationImpl {
+    pub fn new(
+        db: Arc<dyn DB + Send + Sync>,
+        kcloak_client: Arc<dyn KcloakClient + Send + Sync>,
+    ) -> Self {
+        ConversationImpl { db, kcloak_client }
+    }
+}
+
+/// Member ids of the conversation, 403 when `user_id` is not one of them.
+pub(crate) async fn ensure_member(
+    db: &(dyn DB + Send + Sync),
+    conversation_id: &str,
+    user_id: &str,
+) -> Result<Vec<String>, BaseError> {
+    let member_ids = db.get_conversation_member_ids(conversation_id).await?;
+    if !member_ids.iter().any(|id| id == user_id) {
+        return Err(BaseError::new(403, "not a member of this conversation"));
+    }
+    Ok(member_ids)
+}
+
+#[async_trait]
+impl Conversation for ConversationImpl {
+    async fn open_direct_conversation(
+        &self,
+        token: &str,
+        friend_id: &str,
+    ) -> Result<ConversationItem, BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        let contact_name = self
+            .db
+            .get_contact_name(&user_id, friend_id)
+            .await?
+            .ok_or(BaseError::new(403, "user is not in your contact list"))?;
+
+        let id = direct_conversation_id(&user_id, friend_id);
+        let member_ids = vec![user_id.clone(), friend_id.to_string()];
+        let mut conversation = self
+            .db
+            .create_conversation(&id, &ConversationKind::Direct, None, &user_id, &member_ids)
+            .await?;
+        // direct conversations are shown under the name the user gave the contact
+        conversation.name = Some(contact_name);
+        Ok(conversation)
+    }
+
+    async fn open_conversation(
+        &self,
+        token: &str,
+        conversation_id: &str,
+    ) -> Result<ConversationItem, BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        ensure_member(self.db.as_ref(), conversation_id, &user_id).await?;
+        self.db.get_conversation_by_id(conversation_id).await
+    }
+
+    async fn create_group_conversation(
+        &self,
+        token: &str,
+        name: &str,
+        member_ids: Vec<String>,
+    ) -> Result<ConversationItem, BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        let name = name.trim();
+        if name.is_empty() {
+            return Err(BaseError::new(400, "group name is required"));
+        }
+
+        let mut member_ids = member_ids;
+        member_ids.push(user_id.clone());
+        member_ids.sort();
+        member_ids.dedup();
+        if member_ids.len() > MAX_GROUP_MEMBERS {
+            return Err(BaseError::new(400, "too many group members"));
+        }
+
+        let id = Uuid::new_v4().to_string();
+        self.db
+            .create_conversation(
+                &id,
+                &ConversationKind::Group,
+                Some(name),
+                &user_id,
+                &member_ids,
+            )
+            .await
+    }
+
+    async fn show_conversation_list(
+        &self,
+        token: &str,
+    ) -> Result<Vec<ConversationItem>, BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        self.db.get_conversations_by_user_id(&user_id).await
+    }
+
+    async fn show_messages(
+        &self,
+        token: &str,
+        conversation_id: &str,
+        before_id: Option<&str>,
+    ) -> Result<MessagePage, BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        ensure_member(self.db.as_ref(), conversation_id, &user_id).await?;
+
+        // fetch one extra row to know whether an older page exists
+        let mut messages = self
+            .db
+            .get_messages_before(
+                conversation_id,
+                &user_id,
+                before_id,
+                MESSAGE_PAGE_SIZE as i64 + 1,
+            )
+            .await?;
+        let has_more = messages.len() > MESSAGE_PAGE_SIZE;
+        messages.truncate(MESSAGE_PAGE_SIZE);
+        messages.reverse();
+
+        let next_cursor = if has_more {
+            messages.first().map(|message| message.id.clone())
+        } else {
+            None
+        };
+
+        Ok(MessagePage {
+            user_id,
+            messages,
+            next_cursor,
+        })
+    }
+}
@@ -0,0 +1,86 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+
+use crate::{db::repository::DB, kcloak_client::KcloakClient, BaseError};
+
+/// Shorter queries would match most of the directory.
+pub const MIN_SEARCH_LENGTH: usize = 2;
+
+/// Number of users returned per search.
+pub const SEARCH_LIMIT: i64 = 20;
+
+#[async_trait]
+pub trait Search {
+    /// Users whose username, first name, last name or email starts with `query`,
+    /// leaving out the caller and their contacts.
+    async fn search_users(&self, token: &str, query: &str) -> Result<Vec<UserItem>, BaseError>;
+}
+
+#[derive(Debug, Clone)]
+pub struct UserItem {
+    pub user_id: String,
+    pub username: String,
+    pub name: String,
+}
+
+pub struct SearchImpl {
+    db: Arc<dyn DB + Send + Sync>,
+    kcloak_client: Arc<dyn KcloakClient + Send + Sync>,
+}
+
+impl SearchImpl {
+    pub fn new(
+        db: Arc<dyn DB + Send + Sync>,
+        kcloak_client: Arc<dyn KcloakClient + Send + Sync>,
+    ) -> Self {
+        SearchImpl { db, kcloak_client }
+    }
+}
+
+#[async_trait]
+impl Search for SearchImpl {
+    async fn search_users(&self, token: &str, query: &str) -> Result<Vec<UserItem>, BaseError> {
+        let user_id = self.kcloak_client.authenticate(token).await?;
+        let pattern = match prefix_pattern(query) {
+            Some(pattern) => pattern,
+            None => return Ok(vec![]),
+        };
+        self.db.search_users(&user_id, &pattern, SEARCH_LIMIT).await
+    }
+}
+
+/// A lowercase `LIKE` pattern matching values starting with `query`, with its
+/// wildcards escaped, or `None` when the query is too short.
+fn prefix_pattern(query: &str) -> Option<String> {
+    let query = query.trim().to_lowercase();
+    if query.chars().count() < MIN_SEARCH_LENGTH {
+        return None;
+    }
+    let mut pattern = String::with_capacity(query.len() + 1);
+    for c in query.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('%');
+    Some(pattern)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_prefix_pattern() {
+        assert_eq!(prefix_pattern(" John "), Some("john%".to_string()));
+        assert_eq!(
+            prefix_pattern("50%_a\\b"),
+            Some("50\\%\\_a\\\\b%".to_string())
+        );
+        assert_eq!(prefix_pattern("j"), None);
+        assert_eq!(prefix_pattern("   "), None);
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use jsonwebtoken::{decode, decode_header, jwk::JwkSet, Algorithm, DecodingKey, Validation};
+use serde_json::{Map, Value};
+use tokio::sync::RwLock;
+
+use crate::{configuration::CoreConfiguration, model::TokenIntrospect, BaseError};
+
+/// How long fetched keys are trusted before they are fetched again.
+const JWKS_TTL: Duration = Duration::from_secs(10 * 60);
+/// Keys are refetched early for an unknown `kid`, at most this often.
+const JWKS_MIN_REFRESH: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct TokenVerifierConfig {
+    pub issuer: String,
+    pub jwks_url: String,
+    pub audience: Vec<String>,
+    pub client_id: String,
+}
+
+impl From<Arc<CoreConfiguration>> for TokenVerifierConfig {
+    fn from(config: Arc<CoreConfiguration>) -> Self {
+        let realm_url = format!("{}/realms/{}", config.keycloak_url, config.keycloak_realm);
+        TokenVerifierConfig {
+            issuer: config
+                .keycloak_issuer
+                .clone()
+                .unwrap_or_else(|| realm_url.clone()),
+            jwks_url: format!("{}/protocol/openid-connect/certs", realm_url),
+            audience: config.keycloak_audience.clone(),
+            client_id: config.keycloak_client_id.to_string(),
+        }
+    }
+}
+
+struct CachedJwks {
+    keys: JwkSet,
+    fetched_at: Option<Instant>,
+}
+
+/// Validates Keycloak access tokens locally against the realm's JWKS.
+pub struct TokenVerifier {
+    config: TokenVerifierConfig,
+    req_client: reqwest::Client,
+    jwks: RwLock<CachedJwks>,
+}
+
+impl TokenVerifier {
+    pub fn new(config: TokenVerifierConfig, req_client: reqwest::Client) -> Self {
+        TokenVerifier {
+            config,
+            req_client,
+            jwks: RwLock::new(CachedJwks {
+                keys: JwkSet { keys: vec![] },
+                fetched_at: None,
+            }),
+        }
+    }
+
+    /// Checks the RS256 signature, `exp`, `iss` and `aud` of an access token.
+    pub async fn verify(&self, token: &str) -> Result<TokenIntrospect, BaseError> {
+        let token = token.trim_start_matches("Bearer ");
+        let claims = self.decode(token, &self.config.audience).await?;
+
+        // refresh and id tokens are signed with the same keys
+        if claims.get("typ").and_then(Value::as_str) != Some("Bearer") {
+            return Err(BaseError::new(401, "not an access token"));
+        }
+        if claims.get("azp").and_then(Value::as_str) != Some(self.config.client_id.as_str()) {
+            return Err(BaseError::new(401, "token was issued to another client"));
+        }
+        into_introspect(claims)
+    }
+
+    /// Checks an id token from the authorization code login was issued to us
+    /// for the login that carried `nonce`, returns the user id (`sub`).
+    pub async fn verify_id_token(&self, token: &str, nonce: &str) -> Result<String, BaseError> {
+        let claims = self
+            .decode(token, std::slice::from_ref(&self.config.client_id))
+            .await?;
+        if claims.get("typ").and_then(Value::as_str) != Some("ID") {
+            return Err(BaseError::new(401, "not an id token"));
+        }
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            return Err(BaseError::new(401, "id token nonce does not match"));
+        }
+        claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or(BaseError::new(401, "id token has no subject"))
+    }
+
+    async fn decode(
+        &self,
+        token: &str,
+        audience: &[String],
+    ) -> Result<Map<String, Value>, BaseError> {
+        let header = decode_header(token)?;
+        if header.alg != Algorithm::RS256 {
+            return Err(BaseError::new(401, "unsupported token algorithm"));
+        }
+        let kid = header
+            .kid
+            .ok_or(BaseError::new(401, "token has no key id"))?;
+        let key = self.decoding_key(&kid).await?;
+
+        let mut validation = Validation::new(Algorithm::RS256);
+        validation.set_issuer(&[&self.config.issuer]);
+        validation.set_audience(audience);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        Ok(decode::<Map<String, Value>>(token, &key, &validation)?.claims)
+    }
+
+    async fn decoding_key(&self, kid: &str) -> Result<DecodingKey, BaseError> {
+        {
+            let jwks = self.jwks.read().await;
+            let fresh = jwks
+                .fetched_at
+                .is_some_and(|fetched_at| fetched_at.elapsed() < JWKS_TTL);
+            if let Some(jwk) = jwks.keys.find(kid).filter(|_| fresh) {
+                return Ok(DecodingKey::from_jwk(jwk)?);
+            }
+        }
+
+        let mut jwks = self.jwks.write().await;
+        // an unknown kid usually means the realm rotated its keys, but a forged
+        // kid must not let anyone hammer Keycloak
+        let recently_fetched = jwks
+            .fetched_at
+            .is_some_and(|fetched_at| fetched_at.elapsed() < JWKS_MIN_REFRESH);
+        if !recently_fetched {
+            tracing::info!("fetching jwks from {}", self.config.jwks_url);
+            let resp = self.req_client.get(&self.config.jwks_url).send().await?;
+            if !resp.status().is_success() {
+                return Err(BaseError::new(503, "failed to fetch signing keys"));
+            }
+            jwks.keys = resp.json::<JwkSet>().await?;
+            jwks.fetched_at = Some(Instant::now());
+        }
+        let jwk = jwks
+            .keys
+            .find(kid)
+            .ok_or(BaseError::new(401, "unknown signing key"))?;
+        Ok(DecodingKey::from_jwk(jwk)?)
+    }
+}
+
+/// Reads `sub` without checking the signature, only for a token Keycloak itself
+/// just accepted, e.g. a refresh token, which is signed with a realm secret we do not hold.
+pub fn unverified_subject(token: &str) -> Option<String> {
+    let mut validation = Validation::default();
+    validation.insecure_disable_signature_validation();
+    validation.validate_exp = false;
+    validation.validate_aud = false;
+    validation.set_required_spec_claims::<&str>(&[]);
+    let claims =
+        decode::<Map<String, Value>>(token, &DecodingKey::from_secret(&[]), &validation).ok()?;
+    claims.claims.get("sub")?.as_str().map(str::to_string)
+}
+
+/// `aud` may be a list in a token but is a single string in `TokenIntrospect`.
+fn into_introspect(mut claims: Map<String, Value>) -> Result<TokenIntrospect, BaseError> {
+    if let Some(Value::Array(aud)) = claims.get("aud") {
+        let aud: Vec<&str> = aud.iter().filter_map(Value::as_str).collect();
+        claims.insert("aud".to_string(), Value::String(aud.join(",")));
+    }
+    claims.insert("active".to_string(), Value::Bool(true));
+    serde_json::from_value(Value::Object(claims)).map_err(|e| BaseError::new(401, &e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_into_introspect_joins_audiences() {
+        let claims = serde_json::json!({
+            "sub": "239f1055-0a6b-4d01-9202-c0fff0a50a26",
+            "aud": ["account", "rchaty"],
+            "typ": "Bearer",
+        });
+        let Value::Object(claims) = claims else {
+            unreachable!()
+        };
+        let introspect = into_introspect(claims).unwrap();
+        assert!(introspect.active);
+        assert_eq!(introspect.aud.as_deref(), Some("account,rchaty"));
+        assert_eq!(
+            introspect.sub.as_deref(),
+            Some("239f1055-0a6b-4d01-9202-c0fff0a50a26")
+        );
+    }
+
+    #[test]
+    fn test_unverified_subject() {
+        let token = jsonwebtoken::encode(
+            &jsonwebtoken::Header::new(Algorithm::HS512),
+            &serde_json::json!({"sub": "239f1055-0a6b-4d01-9202-c0fff0a50a26", "typ": "Refresh"}),
+            &jsonwebtoken::EncodingKey::from_secret(b"realm-secret"),
+        )
+        .unwrap();
+        assert_eq!(
+            unverified_subject(&token).as_deref(),
+            Some("239f1055-0a6b-4d01-9202-c0fff0a50a26")
+        );
+        assert_eq!(unverified_subject("not-a-token"), None);
+    }
+}
@@ -1,2 +1,5 @@
 pub mod hmac;
+pub mod pkce;
+pub mod random;
 pub mod signature;
+pub mod throttle;
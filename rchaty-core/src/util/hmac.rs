@@ -1,50 +1,107 @@
+use std::fmt::Display;
+
 use crate::{util::signature::Signature, BaseError};
+use async_trait::async_trait;
 use base64::prelude::*;
 use hmac::{Hmac, Mac};
 use sha2::Sha256;
 
 type HmacSha256 = Hmac<Sha256>;
 
+/// A secret used to sign, `id` is embedded in signatures to find it again.
+#[derive(Clone, Debug, PartialEq)]
+pub struct SigningKey {
+    pub id: String,
+    pub secret: String,
+}
+
+impl SigningKey {
+    pub fn new(id: &str, secret: &str) -> Self {
+        Self {
+            id: id.to_string(),
+            secret: secret.to_string(),
+        }
+    }
+
+    /// Parses a key written as `id:secret`.
+    pub fn parse(key: &str) -> Result<Self, BaseError> {
+        match key.trim().split_once(':') {
+            Some((id, secret)) if !id.is_empty() && !id.contains('.') && !secret.is_empty() => {
+                Ok(Self::new(id, secret))
+            }
+            _ => Err(BaseError::new(500, "signing key must look like id:secret")),
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct HmacSignatureImpl {
     key: String,
+    key_id: Option<String>,
+    previous_keys: Vec<SigningKey>,
 }
 
 impl HmacSignatureImpl {
+    /// Signs with a single key and no key id in the signatures.
     pub fn new(key: String) -> Self {
-        Self { key }
+        Self {
+            key,
+            key_id: None,
+            previous_keys: vec![],
+        }
+    }
+
+    /// Signs with `current` as `key_id.signature`, signatures of `previous` keys
+    /// still verify so rotating does not invalidate links already sent out.
+    pub fn with_rotation(current: SigningKey, previous: Vec<SigningKey>) -> Self {
+        Self {
+            key: current.secret,
+            key_id: Some(current.id),
+            previous_keys: previous,
+        }
+    }
+
+    fn mac(key: &str) -> Result<HmacSha256, BaseError> {
+        HmacSha256::new_from_slice(key.as_bytes()).map_err(|e| BaseError {
+            code: 500,
+            messages: e.to_string(),
+        })
+    }
+
+    fn verification_key(&self, key_id: Option<&str>) -> Result<&str, BaseError> {
+        match (key_id, self.key_id.as_deref()) {
+            (None, None) => Ok(&self.key),
+            (Some(key_id), Some(current)) if key_id == current => Ok(&self.key),
+            (Some(key_id), _) => self
+                .previous_keys
+                .iter()
+                .find(|key| key.id == key_id)
+                .map(|key| key.secret.as_str())
+                .ok_or(BaseError::new(400, "unknown signing key")),
+            (None, Some(_)) => Err(BaseError::new(400, "signature has no key id")),
+        }
     }
 }
 
 impl Signature for HmacSignatureImpl {
     fn sign(&self, data: &str) -> Result<String, BaseError> {
-        let new_from_slice = HmacSha256::new_from_slice(self.key.as_bytes());
-        let mut mac = match new_from_slice {
-            Ok(mac) => mac,
-            Err(e) => {
-                return Err(BaseError {
-                    code: 500,
-                    messages: e.to_string(),
-                })
-            }
-        };
+        let mut mac = Self::mac(&self.key)?;
         mac.update(data.as_bytes());
         let result = mac.finalize().into_bytes();
         let code = BASE64_URL_SAFE.encode(result);
-        Ok(code)
+        match &self.key_id {
+            Some(key_id) => Ok(format!("{}.{}", key_id, code)),
+            None => Ok(code),
+        }
     }
 
     fn verify(&self, data: &str, signature: &str) -> Result<(), BaseError> {
-        let key = HmacSha256::new_from_slice(self.key.as_bytes());
-        let mut mac = match key {
-            Ok(mac) => mac,
-            Err(e) => {
-                return Err(BaseError {
-                    code: 500,
-                    messages: e.to_string(),
-                })
-            }
+        // base64 never contains a dot, so one means the signature carries a key id
+        let (key_id, signature) = match signature.split_once('.') {
+            Some((key_id, signature)) => (Some(key_id), signature),
+            None => (None, signature),
         };
+        let mut mac = Self::mac(self.verification_key(key_id)?)?;
         mac.update(data.as_bytes());
         let signature = BASE64_URL_SAFE.decode(signature)?;
         mac.verify_slice(&signature)?;
@@ -52,6 +109,110 @@ impl Signature for HmacSignatureImpl {
     }
 }
 
+/// A signature that expires and carries a nonce so it can only be used once,
+/// serialized as `issued_at.nonce.signature`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ExpiringSignature {
+    pub issued_at: i64,
+    pub nonce: String,
+    pub signature: String,
+}
+
+impl Display for ExpiringSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.issued_at, self.nonce, self.signature)
+    }
+}
+
+impl ExpiringSignature {
+    pub fn parse(token: &str) -> Result<Self, BaseError> {
+        let invalid = || BaseError::new(400, "invalid token");
+        let mut parts = token.splitn(3, '.');
+        let issued_at = parts
+            .next()
+            .and_then(|issued_at| issued_at.parse().ok())
+            .ok_or_else(invalid)?;
+        let nonce = parts.next().filter(|nonce| !nonce.is_empty());
+        let signature = parts.next().filter(|signature| !signature.is_empty());
+        match (nonce, signature) {
+            (Some(nonce), Some(signature)) => Ok(Self {
+                issued_at,
+                nonce: nonce.to_string(),
+                signature: signature.to_string(),
+            }),
+            _ => Err(invalid()),
+        }
+    }
+
+    fn payload(data: &str, issued_at: i64, nonce: &str) -> String {
+        format!("{}:{}:{}", data, issued_at, nonce)
+    }
+}
+
+/// Remembers the nonces of single-use signatures that were already consumed.
+#[async_trait]
+pub trait NonceStore {
+    /// Marks the nonce as used, `false` when it already was.
+    async fn consume_nonce(&self, nonce: &str, expires_at: i64) -> Result<bool, BaseError>;
+}
+
+impl HmacSignatureImpl {
+    pub fn sign_expiring(
+        &self,
+        data: &str,
+        issued_at: i64,
+        nonce: &str,
+    ) -> Result<ExpiringSignature, BaseError> {
+        let signature = self.sign(&ExpiringSignature::payload(data, issued_at, nonce))?;
+        Ok(ExpiringSignature {
+            issued_at,
+            nonce: nonce.to_string(),
+            signature,
+        })
+    }
+
+    /// Checks the signature and that it was issued less than `max_age` seconds before `now`.
+    pub fn verify_expiring(
+        &self,
+        data: &str,
+        token: &str,
+        now: i64,
+        max_age: i64,
+    ) -> Result<ExpiringSignature, BaseError> {
+        let token = ExpiringSignature::parse(token)?;
+        self.verify(
+            &ExpiringSignature::payload(data, token.issued_at, &token.nonce),
+            &token.signature,
+        )?;
+        if token.issued_at > now || now - token.issued_at > max_age {
+            return Err(BaseError::new(400, "token expired"));
+        }
+        Ok(token)
+    }
+
+    /// Like `verify_expiring`, and rejects a token that was already used.
+    pub async fn verify_once<S>(
+        &self,
+        data: &str,
+        token: &str,
+        now: i64,
+        max_age: i64,
+        store: &S,
+    ) -> Result<(), BaseError>
+    where
+        S: NonceStore + Sync + ?Sized,
+    {
+        let token = self.verify_expiring(data, token, now, max_age)?;
+        let consumed = store
+            .consume_nonce(&token.nonce, token.issued_at + max_age)
+            .await?;
+        if !consumed {
+            return Err(BaseError::new(400, "token already used"));
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -76,4 +237,181 @@ mod tests {
         let res = hmac.verify(data, signature);
         assert!(res.is_ok());
     }
+
+    use std::{collections::HashSet, sync::Mutex};
+
+    const ISSUED_AT: i64 = 1_700_000_000;
+    const MAX_AGE: i64 = 60 * 60;
+
+    #[derive(Default)]
+    struct MemoryNonceStore {
+        nonces: Mutex<HashSet<String>>,
+    }
+
+    #[async_trait]
+    impl NonceStore for MemoryNonceStore {
+        async fn consume_nonce(&self, nonce: &str, _expires_at: i64) -> Result<bool, BaseError> {
+            Ok(self.nonces.lock().unwrap().insert(nonce.to_string()))
+        }
+    }
+
+    #[test]
+    fn test_expiring_signature_roundtrip() {
+        let hmac = HmacSignatureImpl::new("admin".to_string());
+        let data = "239f1055-0a6b-4d01-9202-c0fff0a50a26";
+        let token = hmac.sign_expiring(data, ISSUED_AT, "nonce").unwrap();
+        let parsed = ExpiringSignature::parse(&token.to_string()).unwrap();
+        assert_eq!(parsed, token);
+
+        let res = hmac.verify_expiring(data, &token.to_string(), ISSUED_AT + MAX_AGE, MAX_AGE);
+        assert!(res.is_ok());
+    }
+
+    #[test]
+    fn test_expiring_signature_expired() {
+        let hmac = HmacSignatureImpl::new("admin".to_string());
+        let data = "239f1055-0a6b-4d01-9202-c0fff0a50a26";
+        let token = hmac.sign_expiring(data, ISSUED_AT, "nonce").unwrap();
+        let res = hmac.verify_expiring(data, &token.to_string(), ISSUED_AT + MAX_AGE + 1, MAX_AGE);
+        assert!(res.is_err());
+
+        // issued in the future
+        let res = hmac.verify_expiring(data, &token.to_string(), ISSUED_AT - 1, MAX_AGE);
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn test_expiring_signature_tampered() {
+        let hmac = HmacSignatureImpl::new("admin".to_string());
+        let data = "239f1055-0a6b-4d01-9202-c0fff0a50a26";
+        let token = hmac.sign_expiring(data, ISSUED_AT, "nonce").unwrap();
+
+        // pushing issued_at forward to extend the lifetime breaks the signature
+        let extended = ExpiringSignature {
+            issued_at: ISSUED_AT + MAX_AGE,
+            ..token.clone()
+        };
+        let now = ISSUED_AT + MAX_AGE + 1;
+        assert!(hmac
+            .verify_expiring(data, &extended.to_string(), now, MAX_AGE)
+            .is_err());
+
+        let other_nonce = ExpiringSignature {
+            nonce: "other".to_string(),
+            ..token.clone()
+        };
+        assert!(hmac
+            .verify_expiring(data, &other_nonce.to_string(), ISSUED_AT, MAX_AGE)
+            .is_err());
+
+        assert!(hmac
+            .verify_expiring("other-user", &token.to_string(), ISSUED_AT, MAX_AGE)
+            .is_err());
+        assert!(hmac
+            .verify_expiring(data, "not-a-token", ISSUED_AT, MAX_AGE)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_expiring_signature_replay() {
+        let hmac = HmacSignatureImpl::new("admin".to_string());
+        let store = MemoryNonceStore::default();
+        let data = "239f1055-0a6b-4d01-9202-c0fff0a50a26";
+        let token = hmac.sign_expiring(data, ISSUED_AT, "nonce").unwrap();
+
+        let res = hmac
+            .verify_once(data, &token.to_string(), ISSUED_AT, MAX_AGE, &store)
+            .await;
+        assert!(res.is_ok());
+
+        let res = hmac
+            .verify_once(data, &token.to_string(), ISSUED_AT, MAX_AGE, &store)
+            .await;
+        assert!(res.is_err());
+
+        // a fresh link for the same user still works
+        let token = hmac.sign_expiring(data, ISSUED_AT, "nonce-2").unwrap();
+        let res = hmac
+            .verify_once(data, &token.to_string(), ISSUED_AT, MAX_AGE, &store)
+            .await;
+        assert!(res.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_expired_token_is_not_consumed() {
+        let hmac = HmacSignatureImpl::new("admin".to_string());
+        let store = MemoryNonceStore::default();
+        let data = "239f1055-0a6b-4d01-9202-c0fff0a50a26";
+        let token = hmac.sign_expiring(data, ISSUED_AT, "nonce").unwrap();
+
+        let res = hmac
+            .verify_once(
+                data,
+                &token.to_string(),
+                ISSUED_AT + MAX_AGE + 1,
+                MAX_AGE,
+                &store,
+            )
+            .await;
+        assert!(res.is_err());
+        assert!(store.nonces.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_signing_key_parse() {
+        assert_eq!(
+            SigningKey::parse("2024-06:secret").unwrap(),
+            SigningKey::new("2024-06", "secret")
+        );
+        assert!(SigningKey::parse("secret").is_err());
+        assert!(SigningKey::parse("key.1:secret").is_err());
+        assert!(SigningKey::parse("key-1:").is_err());
+    }
+
+    #[test]
+    fn test_rotated_key_still_verifies() {
+        let data = "239f1055-0a6b-4d01-9202-c0fff0a50a26";
+        let old = HmacSignatureImpl::with_rotation(SigningKey::new("k1", "old-secret"), vec![]);
+        let signature = old.sign(data).unwrap();
+        assert!(signature.starts_with("k1."));
+
+        let rotated = HmacSignatureImpl::with_rotation(
+            SigningKey::new("k2", "new-secret"),
+            vec![SigningKey::new("k1", "old-secret")],
+        );
+        assert!(rotated.verify(data, &signature).is_ok());
+        let new_signature = rotated.sign(data).unwrap();
+        assert!(new_signature.starts_with("k2."));
+        assert!(rotated.verify(data, &new_signature).is_ok());
+
+        // once the old key is dropped its signatures stop verifying
+        let retired = HmacSignatureImpl::with_rotation(SigningKey::new("k2", "new-secret"), vec![]);
+        assert!(retired.verify(data, &signature).is_err());
+        assert!(retired.verify(data, &new_signature).is_ok());
+    }
+
+    #[test]
+    fn test_key_id_cannot_be_swapped() {
+        let data = "239f1055-0a6b-4d01-9202-c0fff0a50a26";
+        let hmac = HmacSignatureImpl::with_rotation(
+            SigningKey::new("k2", "new-secret"),
+            vec![SigningKey::new("k1", "old-secret")],
+        );
+        let signature = hmac.sign(data).unwrap();
+        let swapped = signature.replacen("k2.", "k1.", 1);
+        assert!(hmac.verify(data, &swapped).is_err());
+        let unknown = signature.replacen("k2.", "k9.", 1);
+        assert!(hmac.verify(data, &unknown).is_err());
+        let stripped = signature.trim_start_matches("k2.");
+        assert!(hmac.verify(data, stripped).is_err());
+    }
+
+    #[test]
+    fn test_expiring_signature_with_key_id() {
+        let hmac = HmacSignatureImpl::with_rotation(SigningKey::new("k1", "secret"), vec![]);
+        let data = "239f1055-0a6b-4d01-9202-c0fff0a50a26";
+        let token = hmac.sign_expiring(data, ISSUED_AT, "nonce").unwrap();
+        let res = hmac.verify_expiring(data, &token.to_string(), ISSUED_AT, MAX_AGE);
+        assert!(res.is_ok());
+    }
 }
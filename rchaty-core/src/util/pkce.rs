@@ -0,0 +1,87 @@
+use base64::prelude::*;
+use sha2::{Digest, Sha256};
+
+use super::random::random_token;
+use crate::BaseError;
+
+/// What the authorization code login has to remember between the redirect to
+/// Keycloak and the callback, kept by the browser in a short-lived cookie.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AuthorizationFlow {
+    pub state: String,
+    pub nonce: String,
+    pub code_verifier: String,
+}
+
+impl AuthorizationFlow {
+    pub fn new() -> Self {
+        AuthorizationFlow {
+            state: random_token(),
+            nonce: random_token(),
+            // 43 characters, the shortest verifier PKCE allows
+            code_verifier: random_token(),
+        }
+    }
+
+    /// The S256 challenge sent with the authorization request.
+    pub fn code_challenge(&self) -> String {
+        BASE64_URL_SAFE_NO_PAD.encode(Sha256::digest(self.code_verifier.as_bytes()))
+    }
+
+    pub fn parse(value: &str) -> Result<Self, BaseError> {
+        let mut parts = value.split('.');
+        match (parts.next(), parts.next(), parts.next(), parts.next()) {
+            (Some(state), Some(nonce), Some(code_verifier), None)
+                if !state.is_empty() && !nonce.is_empty() && !code_verifier.is_empty() =>
+            {
+                Ok(AuthorizationFlow {
+                    state: state.to_string(),
+                    nonce: nonce.to_string(),
+                    code_verifier: code_verifier.to_string(),
+                })
+            }
+            _ => Err(BaseError::new(400, "invalid login flow")),
+        }
+    }
+}
+
+impl Default for AuthorizationFlow {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Display for AuthorizationFlow {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}.{}.{}", self.state, self.nonce, self.code_verifier)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    #[test]
+    fn test_flow_round_trip() {
+        let flow = AuthorizationFlow::new();
+        assert_eq!(flow.code_verifier.len(), 43);
+        assert_ne!(flow.state, flow.nonce);
+        assert_eq!(AuthorizationFlow::parse(&flow.to_string()).unwrap(), flow);
+        assert!(AuthorizationFlow::parse("state.nonce").is_err());
+        assert!(AuthorizationFlow::parse("state..verifier").is_err());
+    }
+
+    #[test]
+    fn test_code_challenge() {
+        let flow = AuthorizationFlow {
+            state: "state".to_string(),
+            nonce: "nonce".to_string(),
+            code_verifier: "dBjftJeZ4CVP-mJ92IIrwpYPsUcLXiHzz-0Vfq3U1vqh0s".to_string(),
+        };
+        assert_eq!(
+            flow.code_challenge(),
+            "ENRGWyN5NS3DeCge244WgFJwvNv3eG0P7aocc4HFxkU"
+        );
+    }
+}
@@ -0,0 +1,11 @@
+use base64::prelude::*;
+use uuid::Uuid;
+
+/// 43 url safe characters out of two random uuids, unguessable and safe in
+/// urls, cookies and headers.
+pub fn random_token() -> String {
+    let mut bytes = Vec::with_capacity(32);
+    bytes.extend_from_slice(Uuid::new_v4().as_bytes());
+    bytes.extend_from_slice(Uuid::new_v4().as_bytes());
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
@@ -0,0 +1,319 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use crate::{configuration::ThrottleConfig, BaseError};
+
+/// Once full, stale entries are purged first, then the least recently seen ones that
+/// are not locked out.
+const THROTTLE_CAPACITY: usize = 4096;
+
+/// Limits attempts per key, e.g. a client ip or a username, and locks a key
+/// out for exponentially longer after repeated failures.
+pub struct Throttle {
+    config: ThrottleConfig,
+    entries: Mutex<HashMap<String, ThrottleEntry>>,
+}
+
+struct ThrottleEntry {
+    window_start: Instant,
+    attempts: u32,
+    failures: u32,
+    locked_until: Option<Instant>,
+    last_seen: Instant,
+}
+
+impl ThrottleEntry {
+    fn new(now: Instant) -> Self {
+        ThrottleEntry {
+            window_start: now,
+            attempts: 0,
+            failures: 0,
+            locked_until: None,
+            last_seen: now,
+        }
+    }
+
+    fn is_locked(&self, now: Instant) -> bool {
+        self.locked_until.is_some_and(|until| until > now)
+    }
+
+    /// Failures are forgotten after a quiet period as long as the longest lockout.
+    fn is_stale(&self, now: Instant, forget_after: Duration) -> bool {
+        self.locked_until.is_none_or(|until| until <= now)
+            && now.duration_since(self.last_seen) >= forget_after
+    }
+}
+
+impl Throttle {
+    pub fn new(config: ThrottleConfig) -> Self {
+        Throttle {
+            config,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts an attempt for every key, unless one of them is locked out or
+    /// already used up its attempts for the current window.
+    pub fn attempt(&self, keys: &[String]) -> Result<(), BaseError> {
+        self.attempt_at(keys, Instant::now())
+    }
+
+    pub fn failed(&self, keys: &[String]) {
+        self.failed_at(keys, Instant::now())
+    }
+
+    /// A success clears the failures, the attempts still count for the window.
+    pub fn succeeded(&self, keys: &[String]) {
+        let mut entries = self.entries.lock().unwrap();
+        for key in keys {
+            if let Some(entry) = entries.get_mut(key) {
+                entry.failures = 0;
+                entry.locked_until = None;
+            }
+        }
+    }
+
+    fn attempt_at(&self, keys: &[String], now: Instant) -> Result<(), BaseError> {
+        let mut entries = self.entries.lock().unwrap();
+        self.make_room(&mut entries, keys, now)
+            .map_err(too_many_attempts)?;
+
+        for key in keys {
+            let Some(entry) = entries.get_mut(key) else {
+                continue;
+            };
+            if entry.is_stale(now, self.config.lockout_max) {
+                *entry = ThrottleEntry::new(now);
+            }
+            if let Some(until) = entry.locked_until.filter(|until| *until > now) {
+                return Err(too_many_attempts(until - now));
+            }
+            if now.duration_since(entry.window_start) >= self.config.window {
+                entry.window_start = now;
+                entry.attempts = 0;
+            }
+            if entry.attempts >= self.config.max_attempts {
+                return Err(too_many_attempts(
+                    entry.window_start + self.config.window - now,
+                ));
+            }
+        }
+
+        for key in keys {
+            let entry = entries
+                .entry(key.clone())
+                .or_insert_with(|| ThrottleEntry::new(now));
+            entry.attempts += 1;
+            entry.last_seen = now;
+        }
+        Ok(())
+    }
+
+    fn failed_at(&self, keys: &[String], now: Instant) {
+        let mut entries = self.entries.lock().unwrap();
+        let room = self.make_room(&mut entries, keys, now).is_ok();
+        for key in keys {
+            if !room && !entries.contains_key(key) {
+                continue;
+            }
+            let entry = entries
+                .entry(key.clone())
+                .or_insert_with(|| ThrottleEntry::new(now));
+            entry.failures += 1;
+            entry.last_seen = now;
+            if entry.failures >= self.config.lockout_threshold {
+                entry.locked_until = Some(now + self.lockout(entry.failures));
+            }
+        }
+    }
+
+    /// Keeps the map within `THROTTLE_CAPACITY` before new `keys` are inserted.
+    ///
+    /// Locked out entries are never evicted, otherwise flooding new keys would lift
+    /// a lockout. When they leave no room, returns how long until the first one ends.
+    fn make_room(
+        &self,
+        entries: &mut HashMap<String, ThrottleEntry>,
+        keys: &[String],
+        now: Instant,
+    ) -> Result<(), Duration> {
+        let missing = keys
+            .iter()
+            .filter(|key| !entries.contains_key(*key))
+            .count();
+        if entries.len() + missing <= THROTTLE_CAPACITY {
+            return Ok(());
+        }
+        let forget_after = self.config.lockout_max;
+        entries.retain(|_, entry| !entry.is_stale(now, forget_after));
+
+        let overflow = (entries.len() + missing).saturating_sub(THROTTLE_CAPACITY);
+        if overflow == 0 {
+            return Ok(());
+        }
+        let mut oldest = entries
+            .iter()
+            .filter(|(key, entry)| !keys.contains(key) && !entry.is_locked(now))
+            .map(|(key, entry)| (entry.last_seen, key.clone()))
+            .collect::<Vec<_>>();
+        if oldest.len() < overflow {
+            let retry_after = entries
+                .values()
+                .filter_map(|entry| entry.locked_until)
+                .filter(|until| *until > now)
+                .min()
+                .map_or(self.config.window, |until| until - now);
+            return Err(retry_after);
+        }
+        oldest.sort_unstable();
+        for (_, key) in oldest.into_iter().take(overflow) {
+            entries.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// `lockout_base` at the threshold, doubling with every further failure.
+    fn lockout(&self, failures: u32) -> Duration {
+        let doublings = (failures - self.config.lockout_threshold).min(16);
+        self.config
+            .lockout_base
+            .saturating_mul(1 << doublings)
+            .min(self.config.lockout_max)
+    }
+}
+
+fn too_many_attempts(retry_after: Duration) -> BaseError {
+    // rounded up so the user is never told to retry while still locked
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    BaseError::new(
+        429,
+        &format!("Too many attempts, please try again in {} seconds", seconds),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn throttle() -> Throttle {
+        Throttle::new(ThrottleConfig {
+            max_attempts: 3,
+            window: Duration::from_secs(60),
+            lockout_threshold: 2,
+            lockout_base: Duration::from_secs(10),
+            lockout_max: Duration::from_secs(25),
+        })
+    }
+
+    fn keys() -> Vec<String> {
+        vec!["ip:127.0.0.1".to_string(), "user:john".to_string()]
+    }
+
+    #[test]
+    fn test_attempts_are_limited_per_window() {
+        let throttle = throttle();
+        let now = Instant::now();
+        for _ in 0..3 {
+            throttle.attempt_at(&keys(), now).unwrap();
+        }
+        let err = throttle.attempt_at(&keys(), now).unwrap_err();
+        assert_eq!(err.code, 429);
+
+        // another user from another ip is not affected
+        let other = vec!["ip:10.0.0.1".to_string(), "user:jane".to_string()];
+        assert!(throttle.attempt_at(&other, now).is_ok());
+
+        assert!(throttle
+            .attempt_at(&keys(), now + Duration::from_secs(60))
+            .is_ok());
+    }
+
+    #[test]
+    fn test_lockout_backs_off_exponentially() {
+        let throttle = throttle();
+        let now = Instant::now();
+        throttle.failed_at(&keys(), now);
+        assert!(throttle.attempt_at(&keys(), now).is_ok());
+
+        throttle.failed_at(&keys(), now);
+        let err = throttle.attempt_at(&keys(), now).unwrap_err();
+        assert!(err.messages.contains("10 seconds"));
+        assert!(throttle
+            .attempt_at(&keys(), now + Duration::from_secs(10))
+            .is_ok());
+
+        throttle.failed_at(&keys(), now + Duration::from_secs(10));
+        let err = throttle
+            .attempt_at(&keys(), now + Duration::from_secs(10))
+            .unwrap_err();
+        assert!(err.messages.contains("20 seconds"));
+
+        // capped at lockout_max
+        throttle.failed_at(&keys(), now + Duration::from_secs(30));
+        let err = throttle
+            .attempt_at(&keys(), now + Duration::from_secs(30))
+            .unwrap_err();
+        assert!(err.messages.contains("25 seconds"));
+    }
+
+    #[test]
+    fn test_entries_are_capped() {
+        let throttle = throttle();
+        let now = Instant::now();
+        for i in 0..THROTTLE_CAPACITY {
+            let key = vec![format!("user:{}", i)];
+            throttle.failed_at(&key, now + Duration::from_millis(i as u64));
+        }
+        let later = now + Duration::from_secs(1);
+        throttle.attempt_at(&keys(), later).unwrap();
+
+        let entries = throttle.entries.lock().unwrap();
+        assert_eq!(entries.len(), THROTTLE_CAPACITY);
+        // the least recently seen entries made room for the new keys
+        assert!(!entries.contains_key("user:0"));
+        assert!(!entries.contains_key("user:1"));
+        assert!(entries.contains_key("user:2"));
+        assert!(entries.contains_key("ip:127.0.0.1"));
+    }
+
+    #[test]
+    fn test_locked_entries_are_never_evicted() {
+        let throttle = throttle();
+        let now = Instant::now();
+        for i in 0..THROTTLE_CAPACITY {
+            let key = vec![format!("user:{}", i)];
+            throttle.failed_at(&key, now);
+            throttle.failed_at(&key, now);
+        }
+        let err = throttle.attempt_at(&keys(), now).unwrap_err();
+        assert_eq!(err.code, 429);
+        assert!(err.messages.contains("10 seconds"));
+        {
+            let entries = throttle.entries.lock().unwrap();
+            assert_eq!(entries.len(), THROTTLE_CAPACITY);
+            assert!(entries.contains_key("user:0"));
+            assert!(!entries.contains_key("ip:127.0.0.1"));
+        }
+
+        // lockouts that ended can make room again
+        let later = now + Duration::from_secs(10);
+        throttle.attempt_at(&keys(), later).unwrap();
+        let entries = throttle.entries.lock().unwrap();
+        assert_eq!(entries.len(), THROTTLE_CAPACITY);
+        assert!(entries.contains_key("ip:127.0.0.1"));
+    }
+
+    #[test]
+    fn test_success_clears_failures() {
+        let throttle = throttle();
+        let now = Instant::now();
+        throttle.failed_at(&keys(), now);
+        throttle.succeeded(&keys());
+        throttle.failed_at(&keys(), now);
+        assert!(throttle.attempt_at(&keys(), now).is_ok());
+    }
+}
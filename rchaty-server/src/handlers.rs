@@ -1,66 +1,153 @@
-use std::sync::Arc;
+use std::net::SocketAddr;
 
 use axum::{
     body::Body,
-    extract::{Query, State},
+    extract::{ConnectInfo, Query, State},
     http::{HeaderMap, StatusCode},
     response::{IntoResponse, Redirect, Response},
     Form, Json,
 };
-use axum_extra::extract::{cookie::Cookie, CookieJar};
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
 
 use rchaty_core::{
-    model::VerifiedEmailCallback, Auth, EmailVerifiedMessage, SigninParams, SignupParams,
+    model::{AuthorizationCallback, ResetPasswordCallback, VerifiedEmailCallback},
+    util::pkce::AuthorizationFlow,
+    Auth, BaseError, EmailVerifiedMessage, ForgotPasswordParams, SigninParams, SignupParams,
+};
+use rchaty_web::htmx::{
+    Alert, ClearAuthToken, RedirectHtmx, ResetPasswordSent, VerifiedEmailChecker,
+    VerifiedEmailSuccess,
 };
-use rchaty_web::htmx::{Alert, StoreAuthToken, VerifiedEmailChecker, VerifiedEmailSuccess};
 
-use crate::model::BaseResp;
+use time::Duration;
+
+use crate::{
+    middleware::{add_session_cookies, parse_auth, parse_ref_auth, remove_session_cookies},
+    model::BaseResp,
+};
 
-#[derive(Clone)]
-pub struct AppState {
-    pub auth: Arc<dyn Auth + Send + Sync>,
+/// Throttled attempts get a 429 so the alert explains the lockout.
+fn auth_error_status(e: &BaseError) -> StatusCode {
+    match e.code {
+        429 => StatusCode::TOO_MANY_REQUESTS,
+        _ => StatusCode::BAD_REQUEST,
+    }
 }
 
-pub async fn signup<S>(State(service): State<S>, Form(params): Form<SignupParams>) -> Response<Body>
+pub async fn signup<S>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(service): State<S>,
+    Form(params): Form<SignupParams>,
+) -> Response<Body>
 where
     S: Auth + Send + Sync,
 {
-    let resp = service.signup(params).await;
-    match resp {
-        Ok(user_id) => return VerifiedEmailChecker::htmx(user_id).into_response(),
-        Err(e) => return (StatusCode::BAD_REQUEST, Alert::htmx(e.messages)).into_response(),
+    let resp = service.signup(params, &addr.ip().to_string()).await;
+    let user_id = match resp {
+        Ok(user_id) => user_id,
+        Err(e) => return (auth_error_status(&e), Alert::htmx(e.messages)).into_response(),
+    };
+    // the new user has no session yet, the ticket lets them watch for the verification
+    match service.ws_ticket(&user_id).await {
+        Ok(ticket) => VerifiedEmailChecker::htmx(user_id, ticket).into_response(),
+        Err(e) => (StatusCode::INTERNAL_SERVER_ERROR, Alert::htmx(e.messages)).into_response(),
     }
 }
 
 pub async fn signin<S>(
     jar: CookieJar,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(service): State<S>,
     Form(params): Form<SigninParams>,
 ) -> Result<(CookieJar, String), Response<Body>>
 where
     S: Auth + Send + Sync,
 {
-    let resp = service.signin(params).await;
+    let resp = service.signin(params, &addr.ip().to_string()).await;
     match resp {
         // Ok(ok) => return Json(BaseResp::ok(ok)).into_response(),
-        Ok(result) => {
-            let token = Cookie::new("authToken", result.token.clone());
-            let refresh_token = Cookie::new("refToken", result.refresh_token.clone());
-            Ok((
-                jar.add(token).add(refresh_token),
-                StoreAuthToken::htmx(
-                    true,
-                    "/home",
-                    result.token,
-                    result.refresh_token,
-                    result.expires_in,
-                ),
-            ))
+        Ok(result) => Ok((
+            add_session_cookies(jar, service.get_session_cookie(), &result),
+            RedirectHtmx::htmx("/home"),
+        )),
+        Err(e) => Err((auth_error_status(&e), Alert::htmx(e.messages)).into_response()),
+    }
+}
+
+/// Cookie keeping the authorization code login flow until Keycloak redirects back.
+const AUTH_FLOW_COOKIE: &str = "authFlow";
+
+pub async fn authorize<S>(
+    jar: CookieJar,
+    State(service): State<S>,
+) -> Result<(CookieJar, Redirect), Redirect>
+where
+    S: Auth + Send + Sync,
+{
+    let (flow, url) = service.start_authorization().map_err(|e| {
+        let msg = format!("/error?msg={}", e);
+        Redirect::to(&msg)
+    })?;
+    // Lax, the callback is a top level navigation coming from Keycloak
+    let cookie = Cookie::build((AUTH_FLOW_COOKIE, flow.to_string()))
+        .path("/auth")
+        .http_only(true)
+        .secure(service.get_session_cookie().secure)
+        .same_site(SameSite::Lax)
+        .max_age(Duration::minutes(10));
+    Ok((jar.add(cookie), Redirect::to(&url)))
+}
+
+pub async fn authorization_callback<S>(
+    jar: CookieJar,
+    Query(params): Query<AuthorizationCallback>,
+    State(service): State<S>,
+) -> Result<(CookieJar, Redirect), Redirect>
+where
+    S: Auth + Send + Sync,
+{
+    let flow = jar
+        .get(AUTH_FLOW_COOKIE)
+        .map(|cookie| AuthorizationFlow::parse(cookie.value()));
+    // the flow is single use, whatever the outcome
+    let jar = jar.remove(Cookie::build(AUTH_FLOW_COOKIE).path("/auth"));
+    let resp = match flow {
+        Some(Ok(flow)) => service.finish_authorization(flow, params).await,
+        Some(Err(e)) => Err(e),
+        None => Err(BaseError::new(400, "login expired, please try again")),
+    };
+    match resp {
+        Ok(result) => Ok((
+            add_session_cookies(jar, service.get_session_cookie(), &result),
+            Redirect::to("/home"),
+        )),
+        Err(e) => {
+            let msg = format!("/error?msg={}", e);
+            Err(Redirect::to(&msg))
         }
-        Err(e) => return Err((StatusCode::BAD_REQUEST, Alert::htmx(e.messages)).into_response()),
     }
 }
 
+pub async fn logout<S>(jar: CookieJar, State(service): State<S>) -> (CookieJar, String)
+where
+    S: Auth + Send + Sync,
+{
+    let token = parse_auth(&jar).await;
+    let refresh_token = parse_ref_auth(&jar).await;
+    // the browser forgets the session even when Keycloak could not be reached
+    if let Err(e) = service
+        .logout(token.as_deref(), refresh_token.as_deref())
+        .await
+    {
+        tracing::warn!("failed to end session: {}", e);
+    }
+    let jar = remove_session_cookies(jar, service.get_session_cookie());
+    (jar, ClearAuthToken::htmx("/login"))
+}
+
 pub async fn send_verify_email<S>(
     headers: HeaderMap,
     State(service): State<S>,
@@ -73,8 +160,8 @@ where
         .map_or_else(|| "", |v| v.to_str().unwrap_or(""));
     let resp = service.send_verify_email(token).await;
     match resp {
-        Ok(_) => return Json(BaseResp::ok_none()),
-        Err(e) => return Json(BaseResp::err(e)),
+        Ok(_) => Json(BaseResp::ok_none()),
+        Err(e) => Json(BaseResp::err(e)),
     }
 }
 
@@ -88,8 +175,8 @@ where
     let token = token.replace("Bearer ", "");
     let resp = service.revoke_token(&token).await;
     match resp {
-        Ok(_) => return Json(BaseResp::ok_none()),
-        Err(e) => return Json(BaseResp::err(e)),
+        Ok(_) => Json(BaseResp::ok_none()),
+        Err(e) => Json(BaseResp::err(e)),
     }
 }
 
@@ -118,10 +205,47 @@ where
         }
     }
     match resp {
-        Ok(_) => return Redirect::to("/login"),
+        Ok(_) => Redirect::to("/login"),
+        Err(e) => {
+            let msg = format!("/error?msg={}", e);
+            Redirect::to(&msg)
+        }
+    }
+}
+
+pub async fn forgot_password<S>(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(service): State<S>,
+    Form(params): Form<ForgotPasswordParams>,
+) -> Response<Body>
+where
+    S: Auth + Send + Sync,
+{
+    let email = params.email.clone();
+    let resp = service
+        .forgot_password(params, &addr.ip().to_string())
+        .await;
+    match resp {
+        Ok(_) => ResetPasswordSent::htmx(&email).into_response(),
+        Err(e) => (auth_error_status(&e), Alert::htmx(e.messages)).into_response(),
+    }
+}
+
+pub async fn callback_reset_password<S>(
+    Query(params): Query<ResetPasswordCallback>,
+    State(service): State<S>,
+) -> Redirect
+where
+    S: Auth + Send + Sync,
+{
+    let resp = service
+        .callback_reset_password(&params.user_id, &params.token)
+        .await;
+    match resp {
+        Ok(_) => Redirect::to("/reset-password-complete"),
         Err(e) => {
             let msg = format!("/error?msg={}", e);
-            return Redirect::to(&msg);
+            Redirect::to(&msg)
         }
     }
 }
@@ -2,18 +2,39 @@ use std::sync::Arc;
 
 use axum::{
     body::Body,
-    extract::State,
-    http::HeaderMap,
+    extract::{Path, Query, State},
+    http::StatusCode,
     response::{IntoResponse, Response},
+    Form,
 };
 use axum_extra::extract::CookieJar;
 use rchaty_core::{
+    chatchannel::model::{Restriction, MESSAGE_DATE_FORMAT},
     kcloak_client::{KcloakClient, KcloakClientImpl},
-    service::service_contact::{Contact, ContactImpl},
+    model::ContactRequestParams,
+    service::{
+        service_contact::{Contact, ContactImpl, MAX_CONTACT_NAME_LENGTH},
+        service_conversation::{
+            Conversation, ConversationImpl, ConversationItem, ConversationKind,
+        },
+        service_search::{Search, SearchImpl, MIN_SEARCH_LENGTH},
+    },
+    BaseError,
+};
+use rchaty_web::htmx::{
+    Alert, ChatHistoryHtmx, ChatMessageHtmx, ContactItemHtmx, ContactListHtmx, ContactRenameHtmx,
+    ContactRequestItemHtmx, ContactRequestsHtmx, ConversationItemHtmx, ConversationListHtmx,
+    ConversationSelectedHtmx, RedirectHtmx, RestrictedUserHtmx, RestrictedUsersHtmx, UserItemHtmx,
+    UserSearchHtmx,
+};
+
+use crate::{
+    middleware::{add_session_cookies, parse_auth, parse_ref_auth},
+    model::{CreateGroupParams, MessageHistoryParams, RenameContactParams, UserSearchParams},
 };
-use rchaty_web::htmx::{ContactItemHtmx, ContactListHtmx, RedirectHtmx, StoreAuthToken};
 
-use crate::middleware::parse_auth;
+/// Date of the last message in the contact list, e.g. "25 Dec".
+pub(crate) const CONTACT_DATE_FORMAT: &str = "%d %b";
 
 pub async fn check_auth() -> Response<Body> {
     ("ok").into_response()
@@ -27,8 +48,63 @@ pub async fn contact_list(jar: CookieJar, State(state): State<Arc<ContactImpl>>)
         Some(token) => token,
         None => return RedirectHtmx::htmx("/login").into_response(),
     };
+    contact_list_fragment(&state, &token).await
+}
+
+pub async fn rename_contact_form(
+    jar: CookieJar,
+    Path(friend_id): Path<String>,
+    State(state): State<Arc<ContactImpl>>,
+) -> Response<Body> {
+    let token = match parse_auth(&jar).await {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+
+    match state.show_contact_name(&token, &friend_id).await {
+        Ok(name) => {
+            ContactRenameHtmx::htmx(&friend_id, &name, MAX_CONTACT_NAME_LENGTH).into_response()
+        }
+        Err(err) if err.code == 404 => {
+            (StatusCode::NOT_FOUND, Alert::htmx(err.messages)).into_response()
+        }
+        Err(err) => (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response(),
+    }
+}
+
+pub async fn rename_contact(
+    jar: CookieJar,
+    Path(friend_id): Path<String>,
+    State(state): State<Arc<ContactImpl>>,
+    Form(params): Form<RenameContactParams>,
+) -> Response<Body> {
+    let token = match parse_auth(&jar).await {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+    if let Err(err) = state.rename_contact(&token, &friend_id, &params.name).await {
+        return (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response();
+    }
+    contact_list_fragment(&state, &token).await
+}
 
-    let contact_list = state.show_contact_list(&token).await;
+pub async fn remove_contact(
+    jar: CookieJar,
+    Path(friend_id): Path<String>,
+    State(state): State<Arc<ContactImpl>>,
+) -> Response<Body> {
+    let token = match parse_auth(&jar).await {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+    if let Err(err) = state.remove_contact(&token, &friend_id).await {
+        return (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response();
+    }
+    contact_list_fragment(&state, &token).await
+}
+
+async fn contact_list_fragment(state: &ContactImpl, token: &str) -> Response<Body> {
+    let contact_list = state.show_contact_list(token).await;
     let contact_list = match contact_list {
         Ok(ok) => ok,
         Err(err) => {
@@ -38,34 +114,371 @@ pub async fn contact_list(jar: CookieJar, State(state): State<Arc<ContactImpl>>)
 
     let contact_list: Vec<ContactItemHtmx> = contact_list
         .iter()
-        .map(|contact| ContactItemHtmx::new(&contact.friend_id, &contact.name))
+        .map(|contact| {
+            let last_seen = contact
+                .last_seen_at
+                .map(|last_seen_at| last_seen_at.format(MESSAGE_DATE_FORMAT).to_string())
+                .unwrap_or_default();
+            let restriction = contact
+                .restriction
+                .map(|restriction| restriction.to_string())
+                .unwrap_or_default();
+            let last_message_at = contact
+                .last_message_at
+                .map(|last_message_at| last_message_at.format(CONTACT_DATE_FORMAT).to_string())
+                .unwrap_or_default();
+            ContactItemHtmx::new(
+                &contact.friend_id,
+                &contact.name,
+                contact.online,
+                last_seen,
+                restriction,
+            )
+            .with_last_message(
+                contact.last_message.as_deref().unwrap_or_default(),
+                last_message_at,
+                contact.unread_count,
+            )
+        })
         .collect();
     ContactListHtmx::htmx(&contact_list).into_response()
 }
 
+pub async fn contact_requests(
+    jar: CookieJar,
+    State(state): State<Arc<ContactImpl>>,
+) -> Response<Body> {
+    let token = match parse_auth(&jar).await {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+    contact_requests_fragment(&state, &token, false).await
+}
+
+pub async fn send_contact_request(
+    jar: CookieJar,
+    State(state): State<Arc<ContactImpl>>,
+    Form(params): Form<ContactRequestParams>,
+) -> Response<Body> {
+    let token = match parse_auth(&jar).await {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+    if let Err(err) = state.send_contact_request(&token, params).await {
+        return (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response();
+    }
+    // their pending request may have been accepted by ours
+    contact_requests_fragment(&state, &token, true).await
+}
+
+pub async fn accept_contact_request(
+    jar: CookieJar,
+    Path(request_id): Path<String>,
+    State(state): State<Arc<ContactImpl>>,
+) -> Response<Body> {
+    let token = match parse_auth(&jar).await {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+    if let Err(err) = state.accept_contact_request(&token, &request_id).await {
+        return (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response();
+    }
+    contact_requests_fragment(&state, &token, true).await
+}
+
+pub async fn decline_contact_request(
+    jar: CookieJar,
+    Path(request_id): Path<String>,
+    State(state): State<Arc<ContactImpl>>,
+) -> Response<Body> {
+    let token = match parse_auth(&jar).await {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+    if let Err(err) = state.decline_contact_request(&token, &request_id).await {
+        return (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response();
+    }
+    contact_requests_fragment(&state, &token, false).await
+}
+
+pub async fn cancel_contact_request(
+    jar: CookieJar,
+    Path(request_id): Path<String>,
+    State(state): State<Arc<ContactImpl>>,
+) -> Response<Body> {
+    let token = match parse_auth(&jar).await {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+    if let Err(err) = state.cancel_contact_request(&token, &request_id).await {
+        return (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response();
+    }
+    contact_requests_fragment(&state, &token, false).await
+}
+
+async fn contact_requests_fragment(
+    state: &ContactImpl,
+    token: &str,
+    refresh_contacts: bool,
+) -> Response<Body> {
+    let requests = match state.show_contact_requests(token).await {
+        Ok(ok) => ok,
+        Err(err) => return (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response(),
+    };
+
+    let incoming: Vec<ContactRequestItemHtmx> = requests
+        .incoming
+        .iter()
+        .map(|request| {
+            ContactRequestItemHtmx::new(&request.id, &request.sender_id, &request.sender_name)
+        })
+        .collect();
+    let outgoing: Vec<ContactRequestItemHtmx> = requests
+        .outgoing
+        .iter()
+        .map(|request| {
+            ContactRequestItemHtmx::new(&request.id, &request.recipient_id, &request.recipient_name)
+        })
+        .collect();
+    ContactRequestsHtmx::htmx(&incoming, &outgoing, refresh_contacts).into_response()
+}
+
+pub async fn restricted_users(
+    jar: CookieJar,
+    State(state): State<Arc<ContactImpl>>,
+) -> Response<Body> {
+    let token = match parse_auth(&jar).await {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+    restricted_users_fragment(&state, &token, false).await
+}
+
+pub async fn restrict_user(
+    jar: CookieJar,
+    Path((user_id, kind)): Path<(String, String)>,
+    State(state): State<Arc<ContactImpl>>,
+) -> Response<Body> {
+    let token = match parse_auth(&jar).await {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+    let resp = match Restriction::parse(&kind) {
+        Ok(restriction) => state.restrict_user(&token, &user_id, restriction).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = resp {
+        return (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response();
+    }
+    restricted_users_fragment(&state, &token, true).await
+}
+
+pub async fn unrestrict_user(
+    jar: CookieJar,
+    Path((user_id, kind)): Path<(String, String)>,
+    State(state): State<Arc<ContactImpl>>,
+) -> Response<Body> {
+    let token = match parse_auth(&jar).await {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+    let resp = match Restriction::parse(&kind) {
+        Ok(restriction) => state.unrestrict_user(&token, &user_id, restriction).await,
+        Err(err) => Err(err),
+    };
+    if let Err(err) = resp {
+        return (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response();
+    }
+    restricted_users_fragment(&state, &token, true).await
+}
+
+async fn restricted_users_fragment(
+    state: &ContactImpl,
+    token: &str,
+    refresh_contacts: bool,
+) -> Response<Body> {
+    let users = match state.show_restricted_users(token).await {
+        Ok(ok) => ok,
+        Err(err) => return (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response(),
+    };
+    let users: Vec<RestrictedUserHtmx> = users
+        .iter()
+        .map(|user| {
+            RestrictedUserHtmx::new(&user.user_id, &user.name, user.restriction.to_string())
+        })
+        .collect();
+    RestrictedUsersHtmx::htmx(&users, refresh_contacts).into_response()
+}
+
+pub async fn search_users(
+    jar: CookieJar,
+    Query(params): Query<UserSearchParams>,
+    State(state): State<Arc<SearchImpl>>,
+) -> Response<Body> {
+    let token = match parse_auth(&jar).await {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+
+    let users = match state.search_users(&token, &params.q).await {
+        Ok(ok) => ok,
+        Err(err) => return (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response(),
+    };
+    let users: Vec<UserItemHtmx> = users
+        .iter()
+        .map(|user| UserItemHtmx::new(&user.user_id, &user.username, &user.name))
+        .collect();
+    let searched = params.q.trim().chars().count() >= MIN_SEARCH_LENGTH;
+    UserSearchHtmx::htmx(&users, searched).into_response()
+}
+
 pub async fn refresh_token(
-    headers: HeaderMap,
+    jar: CookieJar,
     State(state): State<Arc<KcloakClientImpl>>,
 ) -> Response<Body> {
-    let token = headers.get("X-Refresh-Token");
-
-    let token = match token {
-        Some(token) => token.to_str().unwrap().to_string(),
+    let token = match parse_ref_auth(&jar).await {
+        Some(token) => token,
         None => return RedirectHtmx::htmx("/login").into_response(),
     };
 
     let token = state.refresh_token(&token).await;
     match token {
-        Ok(ok) => {
-            return StoreAuthToken::htmx(
-                true,
-                "/home",
-                ok.access_token,
-                ok.refresh_token,
-                ok.expires_in,
-            )
-            .into_response();
+        Ok(ok) => (
+            add_session_cookies(jar, &state.config.session_cookie, &ok.into()),
+            RedirectHtmx::htmx("/home"),
+        )
+            .into_response(),
+        Err(_) => RedirectHtmx::htmx("/login").into_response(),
+    }
+}
+
+pub async fn conversation_list(
+    jar: CookieJar,
+    State(state): State<Arc<ConversationImpl>>,
+) -> Response<Body> {
+    let token = parse_auth(&jar).await;
+    let token = match token {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+
+    let conversations = state.show_conversation_list(&token).await;
+    let conversations = match conversations {
+        Ok(ok) => ok,
+        Err(err) => {
+            return err.messages.into_response();
         }
-        Err(_) => return RedirectHtmx::htmx("/login").into_response(),
     };
+
+    // direct conversations are already reachable from the contact list
+    let conversations: Vec<ConversationItemHtmx> = conversations
+        .iter()
+        .filter(|conversation| conversation.kind == ConversationKind::Group)
+        .map(|conversation| {
+            ConversationItemHtmx::new(
+                &conversation.id,
+                conversation.name.as_deref().unwrap_or_default(),
+            )
+        })
+        .collect();
+    ConversationListHtmx::htmx(&conversations).into_response()
+}
+
+pub async fn open_direct_conversation(
+    jar: CookieJar,
+    Path(friend_id): Path<String>,
+    State(state): State<Arc<ConversationImpl>>,
+) -> Response<Body> {
+    let token = parse_auth(&jar).await;
+    let token = match token {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+
+    let conversation = state.open_direct_conversation(&token, &friend_id).await;
+    conversation_selected(conversation)
+}
+
+pub async fn open_conversation(
+    jar: CookieJar,
+    Path(conversation_id): Path<String>,
+    State(state): State<Arc<ConversationImpl>>,
+) -> Response<Body> {
+    let token = parse_auth(&jar).await;
+    let token = match token {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+
+    let conversation = state.open_conversation(&token, &conversation_id).await;
+    conversation_selected(conversation)
+}
+
+pub async fn create_group_conversation(
+    jar: CookieJar,
+    State(state): State<Arc<ConversationImpl>>,
+    Form(params): Form<CreateGroupParams>,
+) -> Response<Body> {
+    let token = parse_auth(&jar).await;
+    let token = match token {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+
+    let conversation = state
+        .create_group_conversation(&token, &params.name, params.member_ids())
+        .await;
+    conversation_selected(conversation)
+}
+
+pub async fn message_history(
+    jar: CookieJar,
+    Path(conversation_id): Path<String>,
+    Query(params): Query<MessageHistoryParams>,
+    State(state): State<Arc<ConversationImpl>>,
+) -> Response<Body> {
+    let token = parse_auth(&jar).await;
+    let token = match token {
+        Some(token) => token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
+
+    let before = params.before.as_deref();
+    let page = state.show_messages(&token, &conversation_id, before).await;
+    let page = match page {
+        Ok(ok) => ok,
+        Err(err) => return (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response(),
+    };
+
+    let messages: Vec<ChatMessageHtmx> = page
+        .messages
+        .iter()
+        .map(|message| {
+            ChatMessageHtmx::new(
+                &message.id,
+                &message.content,
+                &message.created_at,
+                message.status.to_string(),
+                message.author.id == page.user_id,
+            )
+        })
+        .collect();
+    ChatHistoryHtmx::htmx(
+        &conversation_id,
+        &messages,
+        page.next_cursor.as_deref(),
+        before.is_none(),
+    )
+    .into_response()
+}
+
+fn conversation_selected(conversation: Result<ConversationItem, BaseError>) -> Response<Body> {
+    match conversation {
+        Ok(conversation) => ConversationSelectedHtmx::htmx(
+            &conversation.id,
+            conversation.name.as_deref().unwrap_or_default(),
+        )
+        .into_response(),
+        Err(err) => (StatusCode::BAD_REQUEST, Alert::htmx(err.messages)).into_response(),
+    }
 }
@@ -2,27 +2,43 @@ use std::sync::Arc;
 
 use axum::{
     extract::{Request, State},
-    http::StatusCode,
+    http::{header::COOKIE, HeaderValue, Method, StatusCode},
     middleware::Next,
-    response::IntoResponse,
+    response::{IntoResponse, Response},
 };
-use axum_extra::extract::{cookie::Cookie, CookieJar};
-use rchaty_core::kcloak_client::{KcloakClient, KcloakClientImpl};
-use rchaty_web::htmx::RedirectHtmx;
+use axum_extra::extract::{
+    cookie::{Cookie, SameSite},
+    CookieJar,
+};
+use rchaty_core::{
+    configuration::SessionCookieConfig,
+    kcloak_client::{KcloakClient, KcloakClientImpl},
+    util::random::random_token,
+    SigninResult,
+};
+use rchaty_web::htmx::{Alert, RedirectHtmx};
+use time::Duration;
+
+pub const AUTH_TOKEN_COOKIE: &str = "authToken";
+pub const REFRESH_TOKEN_COOKIE: &str = "refToken";
+pub const CSRF_COOKIE: &str = "csrfToken";
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// CSRF token of the browser session, rendered into pages so HTMX sends it back.
+#[derive(Debug, Clone)]
+pub struct CsrfToken(pub String);
 
 pub async fn parse_auth(jar: &CookieJar) -> Option<String> {
-    return parse_auth_header((jar, "authToken")).await;
+    return parse_auth_header((jar, AUTH_TOKEN_COOKIE)).await;
 }
 
 pub async fn parse_ref_auth(jar: &CookieJar) -> Option<String> {
-    return parse_auth_header((jar, "refToken")).await;
+    return parse_auth_header((jar, REFRESH_TOKEN_COOKIE)).await;
 }
 
 pub async fn parse_auth_header(jar: (&CookieJar, &str)) -> Option<String> {
     let token = jar.0.get(jar.1);
-    if token.is_none() {
-        return None;
-    }
+    token?;
 
     let token = token.unwrap();
     if !token.value().is_empty() {
@@ -32,57 +48,142 @@ pub async fn parse_auth_header(jar: (&CookieJar, &str)) -> Option<String> {
     None
 }
 
+fn session_cookie<'a>(config: &SessionCookieConfig, name: &'a str, value: String) -> Cookie<'a> {
+    let mut cookie = Cookie::build((name, value))
+        .path(config.path.clone())
+        .http_only(true)
+        .secure(config.secure)
+        .same_site(SameSite::Lax)
+        .build();
+    if let Some(domain) = &config.domain {
+        cookie.set_domain(domain.clone());
+    }
+    cookie
+}
+
+/// Tokens only live in HttpOnly cookies, each expiring with the token it holds.
+pub fn add_session_cookies(
+    jar: CookieJar,
+    config: &SessionCookieConfig,
+    session: &SigninResult,
+) -> CookieJar {
+    let mut token = session_cookie(config, AUTH_TOKEN_COOKIE, session.token.clone());
+    token.set_max_age(Duration::seconds(session.expires_in));
+    let mut refresh_token =
+        session_cookie(config, REFRESH_TOKEN_COOKIE, session.refresh_token.clone());
+    // offline tokens do not expire, keep them for the browser session only
+    if session.refresh_expires_in > 0 {
+        refresh_token.set_max_age(Duration::seconds(session.refresh_expires_in));
+    }
+    jar.add(token).add(refresh_token)
+}
+
+pub fn remove_session_cookies(jar: CookieJar, config: &SessionCookieConfig) -> CookieJar {
+    jar.remove(session_cookie(config, AUTH_TOKEN_COOKIE, String::new()))
+        .remove(session_cookie(config, REFRESH_TOKEN_COOKIE, String::new()))
+}
+
 pub async fn auth_htmx_middleware(
     jar: CookieJar,
     State(state): State<Arc<KcloakClientImpl>>,
-    request: Request,
+    mut request: Request,
     next: Next,
 ) -> impl IntoResponse {
-    {
-        let token = parse_auth(&jar).await;
-        let token = match token {
-            Some(token) => token,
-            None => return RedirectHtmx::htmx("/login").into_response(),
-        };
-
+    if let Some(token) = parse_auth(&jar).await {
         tracing::info!("using auth token");
-        let introspect = state
-            .introspect(&token)
-            .await
-            .map_err(|_| StatusCode::UNAUTHORIZED);
-
-        let introspect = match introspect {
-            Ok(introspect) => introspect,
-            Err(_) => return RedirectHtmx::htmx("/login").into_response(),
-        };
-
-        tracing::info!("introspect: {:?}", introspect.name);
-        if introspect.active {
-            return next.run(request).await;
+        // an expired access token is not fatal, the refresh token below may still be valid
+        match state.authenticate(&token).await {
+            Ok(user_id) => {
+                tracing::debug!("authenticated user: {}", user_id);
+                return next.run(request).await;
+            }
+            Err(e) => tracing::info!("access token rejected: {}", e),
         }
-    };
+    }
 
-    {
-        let ref_token = parse_ref_auth(&jar).await;
-        let ref_token = match ref_token {
-            Some(ref_token) => ref_token,
-            None => return RedirectHtmx::htmx("/login").into_response(),
-        };
+    // the access token cookie is gone once the token expired
+    let ref_token = match parse_ref_auth(&jar).await {
+        Some(ref_token) => ref_token,
+        None => return RedirectHtmx::htmx("/login").into_response(),
+    };
 
-        let ref_token = state.refresh_token(&ref_token).await;
-        tracing::info!("using refresh token");
-        match ref_token {
-            Ok(ok) => {
-                let response = next.run(request).await;
-                let new_cookies = jar
-                    .add(Cookie::new("authToken", ok.access_token))
-                    .add(Cookie::new("refToken", ok.refresh_token));
-                (new_cookies, response).into_response()
-            }
-            Err(err) => {
-                tracing::warn!("failed to refresh token err={}", err);
-                return RedirectHtmx::htmx("/login").into_response();
+    let ref_token = state.refresh_token(&ref_token).await;
+    tracing::info!("using refresh token");
+    match ref_token {
+        Ok(ok) => {
+            let new_cookies = add_session_cookies(jar, &state.config.session_cookie, &ok.into());
+            // handlers read the token from the request, hand them the refreshed one
+            let cookie_header = new_cookies
+                .iter()
+                .map(|cookie| format!("{}={}", cookie.name(), cookie.value()))
+                .collect::<Vec<_>>()
+                .join("; ");
+            if let Ok(cookie_header) = HeaderValue::from_str(&cookie_header) {
+                request.headers_mut().insert(COOKIE, cookie_header);
             }
+            let response = next.run(request).await;
+            (new_cookies, response).into_response()
+        }
+        Err(err) => {
+            tracing::warn!("failed to refresh token err={}", err);
+            RedirectHtmx::htmx("/login").into_response()
         }
     }
 }
+
+/// Issues a CSRF token per browser session and rejects state-changing requests
+/// that do not echo it in the `X-CSRF-Token` header.
+///
+/// The token sits in an HttpOnly cookie another site cannot read, pages get it
+/// through the `CsrfToken` extension and hand it to HTMX with `hx-headers`.
+pub async fn csrf_middleware(
+    jar: CookieJar,
+    State(config): State<SessionCookieConfig>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let token = jar
+        .get(CSRF_COOKIE)
+        .map(|cookie| cookie.value().to_string())
+        .filter(|token| !token.is_empty());
+
+    let safe = matches!(
+        *request.method(),
+        Method::GET | Method::HEAD | Method::OPTIONS
+    );
+    if !safe {
+        let given = request
+            .headers()
+            .get(CSRF_HEADER)
+            .and_then(|given| given.to_str().ok());
+        let valid = match (&token, given) {
+            (Some(token), Some(given)) => constant_time_eq(token.as_bytes(), given.as_bytes()),
+            _ => false,
+        };
+        if !valid {
+            tracing::warn!(
+                "rejected {} {} without a valid csrf token",
+                request.method(),
+                request.uri().path()
+            );
+            let alert = Alert::htmx("Your session expired, please reload the page".to_string());
+            return (StatusCode::FORBIDDEN, alert).into_response();
+        }
+    }
+
+    let (token, issued) = match token {
+        Some(token) => (token, false),
+        None => (random_token(), true),
+    };
+    request.extensions_mut().insert(CsrfToken(token.clone()));
+    let response = next.run(request).await;
+    if issued {
+        let jar = jar.add(session_cookie(&config, CSRF_COOKIE, token));
+        return (jar, response).into_response();
+    }
+    response
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
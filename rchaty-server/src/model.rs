@@ -1,6 +1,11 @@
 use rchaty_core::BaseError;
 use serde::{Deserialize, Serialize};
 
+#[derive(Debug, Deserialize)]
+pub struct WsTicketParams {
+    pub ticket: Option<String>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct BaseResp<T> {
     pub status: String,
@@ -42,3 +47,36 @@ impl Default for BaseResp<String> {
         }
     }
 }
+
+#[derive(Debug, Deserialize)]
+pub struct CreateGroupParams {
+    pub name: String,
+    /// Comma separated user ids.
+    pub member_ids: String,
+}
+
+impl CreateGroupParams {
+    pub fn member_ids(&self) -> Vec<String> {
+        self.member_ids
+            .split(',')
+            .map(|id| id.trim().to_string())
+            .filter(|id| !id.is_empty())
+            .collect()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RenameContactParams {
+    pub name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserSearchParams {
+    #[serde(default)]
+    pub q: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MessageHistoryParams {
+    pub before: Option<String>,
+}
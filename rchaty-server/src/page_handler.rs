@@ -1,28 +1,43 @@
 use askama::Template;
-use axum::{extract::Query, response::Html};
+use axum::{
+    extract::{Query, State},
+    response::{Html, IntoResponse, Redirect, Response},
+    Extension,
+};
+use axum_extra::extract::CookieJar;
+use rchaty_core::Auth;
 use rchaty_web::{
     error::Page404Template,
     htmx::LoginClicked,
-    page::{HomeTemplate, LoginTemplate, SignupTemplate},
+    page::{
+        ForgotPasswordTemplate, HomeTemplate, LoginTemplate, ResetPasswordCompleteTemplate,
+        SignupTemplate,
+    },
     ErrorTemplate,
 };
 use serde::{Deserialize, Serialize};
 
+use crate::middleware::{add_session_cookies, parse_auth, parse_ref_auth, CsrfToken};
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct ErrorPageQueryParams {
     pub msg: String,
 }
 
 pub async fn error_page(
+    Extension(csrf): Extension<CsrfToken>,
     Query(params): Query<ErrorPageQueryParams>,
 ) -> axum::response::Html<String> {
-    let template = ErrorTemplate { error: &params.msg };
+    let template = ErrorTemplate {
+        error: &params.msg,
+        csrf_token: csrf.0,
+    };
     let html = template.render().unwrap();
     Html(html)
 }
 
-pub async fn login_page() -> axum::response::Html<String> {
-    let template = LoginTemplate {};
+pub async fn login_page(Extension(csrf): Extension<CsrfToken>) -> axum::response::Html<String> {
+    let template = LoginTemplate { csrf_token: csrf.0 };
     let html = template.render().unwrap();
     Html(html)
 }
@@ -33,20 +48,77 @@ pub async fn htmx_login_cliked() -> axum::response::Html<String> {
     Html(html)
 }
 
-pub async fn page_404() -> axum::response::Html<String> {
-    let template = Page404Template {};
+pub async fn page_404(Extension(csrf): Extension<CsrfToken>) -> axum::response::Html<String> {
+    let template = Page404Template { csrf_token: csrf.0 };
     let html = template.render().unwrap();
     Html(html)
 }
 
-pub async fn signup_page() -> axum::response::Html<String> {
-    let template = SignupTemplate {};
+pub async fn signup_page(Extension(csrf): Extension<CsrfToken>) -> axum::response::Html<String> {
+    let template = SignupTemplate { csrf_token: csrf.0 };
     let html = template.render().unwrap();
     Html(html)
 }
 
-pub async fn home_page() -> axum::response::Html<String> {
-    let template = HomeTemplate {};
+pub async fn forgot_password_page(
+    Extension(csrf): Extension<CsrfToken>,
+) -> axum::response::Html<String> {
+    let template = ForgotPasswordTemplate { csrf_token: csrf.0 };
     let html = template.render().unwrap();
     Html(html)
 }
+
+pub async fn reset_password_complete_page(
+    Extension(csrf): Extension<CsrfToken>,
+) -> axum::response::Html<String> {
+    let template = ResetPasswordCompleteTemplate { csrf_token: csrf.0 };
+    let html = template.render().unwrap();
+    Html(html)
+}
+
+/// Renders the signed in user's id into the page, refreshing the session when the
+/// access token already expired.
+pub async fn home_page<S>(
+    jar: CookieJar,
+    Extension(csrf): Extension<CsrfToken>,
+    State(service): State<S>,
+) -> Response
+where
+    S: Auth + Send + Sync,
+{
+    if let Some(token) = parse_auth(&jar).await {
+        if let Ok(user_id) = service.authenticate(&token).await {
+            let template = HomeTemplate {
+                user_id,
+                csrf_token: csrf.0,
+            };
+            return Html(template.render().unwrap()).into_response();
+        }
+    }
+
+    let session = match parse_ref_auth(&jar).await {
+        Some(refresh_token) => service.refresh(&refresh_token).await,
+        None => return Redirect::to("/login").into_response(),
+    };
+    let session = match session {
+        Ok(session) => session,
+        Err(e) => {
+            tracing::info!("failed to refresh session: {}", e);
+            return Redirect::to("/login").into_response();
+        }
+    };
+    match service.authenticate(&session.token).await {
+        Ok(user_id) => {
+            let template = HomeTemplate {
+                user_id,
+                csrf_token: csrf.0,
+            };
+            (
+                add_session_cookies(jar, service.get_session_cookie(), &session),
+                Html(template.render().unwrap()),
+            )
+                .into_response()
+        }
+        Err(_) => Redirect::to("/login").into_response(),
+    }
+}
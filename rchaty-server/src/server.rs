@@ -1,26 +1,46 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use crate::{
-    handlers::{callback_verify_email, revoke_token, send_verify_email, signin, signup},
-    htmx_handler::{check_auth, contact_list, refresh_token},
-    middleware::auth_htmx_middleware,
-    page_handler::{error_page, home_page, htmx_login_cliked, login_page, page_404, signup_page},
-    ws_handler::{chat_handler, contact_list_handler, email_checker_handler},
+    handlers::{
+        authorization_callback, authorize, callback_reset_password, callback_verify_email,
+        forgot_password, logout, revoke_token, send_verify_email, signin, signup,
+    },
+    htmx_handler::{
+        accept_contact_request, cancel_contact_request, check_auth, contact_list, contact_requests,
+        conversation_list, create_group_conversation, decline_contact_request, message_history,
+        open_conversation, open_direct_conversation, refresh_token, remove_contact, rename_contact,
+        rename_contact_form, restrict_user, restricted_users, search_users, send_contact_request,
+        unrestrict_user,
+    },
+    middleware::{auth_htmx_middleware, csrf_middleware},
+    page_handler::{
+        error_page, forgot_password_page, home_page, htmx_login_cliked, login_page, page_404,
+        reset_password_complete_page, signup_page,
+    },
+    ws_handler::{chat_handler, email_checker_handler},
     ws_mock_handler::{mock_chat_handler_sender, mock_email_checker_handler},
 };
 use axum::{
     middleware,
-    routing::{get, post},
+    routing::{get, post, put},
     Router,
 };
 
 use rchaty_core::{
-    chatchannel::master::MasterChannelImpl,
-    configuration::CoreConfiguration,
+    chatchannel::{
+        master::{MasterChannel, MasterChannelImpl},
+        pg_master::PgMasterChannelImpl,
+        presence::PresenceImpl,
+    },
+    configuration::{CoreConfiguration, MasterChannelKind},
     db::repository::{DBImpl, DB},
     kcloak::KcloakImpl,
     kcloak_client::KcloakClientImpl,
-    service::service_contact::ContactImpl,
+    service::{
+        service_chat::ChatImpl, service_contact::ContactImpl,
+        service_conversation::ConversationImpl, service_search::SearchImpl,
+    },
+    util::throttle::Throttle,
     AuthImpl, EmailVerifiedChannelImpl,
 };
 use tokio::net::TcpListener;
@@ -45,8 +65,37 @@ pub async fn run() {
             .expect("Error initializing kcloak client"),
     );
 
+    // master_channel
+    let master_channel: Arc<dyn MasterChannel + Send + Sync> = match config.master_channel {
+        MasterChannelKind::Local => {
+            let master_channel = MasterChannelImpl::new();
+            master_channel.spawn_gc();
+            Arc::new(master_channel)
+        }
+        MasterChannelKind::Postgres => Arc::new(PgMasterChannelImpl::connect(
+            Arc::clone(&config).into(),
+            db.clone(),
+        )),
+    };
+
+    // presence
+    let presence = PresenceImpl::new(db.clone(), master_channel.clone());
+    presence.spawn_heartbeat();
+    let presence = Arc::new(presence);
+
     // contact_service
-    let contact_service = Arc::new(ContactImpl::new(db.clone(), kcloak_client.clone()));
+    let contact_service = Arc::new(ContactImpl::new(
+        db.clone(),
+        kcloak_client.clone(),
+        presence.clone(),
+        master_channel.clone(),
+    ));
+
+    // conversation_service
+    let conversation_service = Arc::new(ConversationImpl::new(db.clone(), kcloak_client.clone()));
+
+    // search_service
+    let search_service = Arc::new(SearchImpl::new(db.clone(), kcloak_client.clone()));
 
     // Initialize Auth
     let auth = {
@@ -61,10 +110,26 @@ pub async fn run() {
             Arc::clone(&kcloak_client),
             Arc::clone(&db),
             email_channel,
+            master_channel.clone(),
+            Throttle::new(config.signin_throttle.clone()),
+            Throttle::new(config.forgot_password_throttle.clone()),
         )
     };
 
-    let master_channel = MasterChannelImpl::new();
+    // users saved before usernames were stored are found by search once filled in
+    {
+        let auth = auth.clone();
+        tokio::spawn(async move { auth.backfill_usernames().await });
+    }
+
+    // chat_service
+    let chat_service = ChatImpl::new(
+        db.clone(),
+        master_channel.clone(),
+        presence.clone(),
+        kcloak_client.clone(),
+    );
+    chat_service.spawn_typing_sweeper();
 
     let guard_htmx_auth = Box::new(middleware::from_fn_with_state(
         Arc::clone(&kcloak_client),
@@ -85,32 +150,110 @@ pub async fn run() {
             "/contact_list",
             get(contact_list).with_state(contact_service.clone()),
         )
-        .layer(*guard_htmx_auth.clone())
-        .with_state(Arc::clone(&kcloak_client));
-
-    let ws = Router::new()
         .route(
-            "/contact_list",
-            get(contact_list_handler::<MasterChannelImpl>),
+            "/contacts/:friend_id",
+            put(rename_contact)
+                .delete(remove_contact)
+                .with_state(contact_service.clone()),
+        )
+        .route(
+            "/contacts/:friend_id/rename",
+            get(rename_contact_form).with_state(contact_service.clone()),
+        )
+        .route(
+            "/contact_requests",
+            get(contact_requests)
+                .post(send_contact_request)
+                .with_state(contact_service.clone()),
+        )
+        .route(
+            "/contact_requests/:request_id/accept",
+            post(accept_contact_request).with_state(contact_service.clone()),
+        )
+        .route(
+            "/contact_requests/:request_id/decline",
+            post(decline_contact_request).with_state(contact_service.clone()),
+        )
+        .route(
+            "/contact_requests/:request_id/cancel",
+            post(cancel_contact_request).with_state(contact_service.clone()),
+        )
+        .route(
+            "/restrictions",
+            get(restricted_users).with_state(contact_service.clone()),
+        )
+        .route(
+            "/restrictions/:user_id/:kind",
+            post(restrict_user)
+                .delete(unrestrict_user)
+                .with_state(contact_service.clone()),
+        )
+        .route(
+            "/users/search",
+            get(search_users).with_state(search_service.clone()),
+        )
+        .route(
+            "/conversation_list",
+            get(conversation_list).with_state(conversation_service.clone()),
+        )
+        .route(
+            "/conversation/direct/:friend_id",
+            get(open_direct_conversation).with_state(conversation_service.clone()),
         )
-        .route("/chat/:user_id", get(chat_handler::<MasterChannelImpl>))
         .route(
-            "/mock/chat/:user_id",
-            get(mock_chat_handler_sender::<MasterChannelImpl>),
+            "/conversation/group",
+            post(create_group_conversation).with_state(conversation_service.clone()),
         )
-        .with_state(master_channel)
-        .route("/vsc/:user_id", get(email_checker_handler::<AuthImpl>))
         .route(
-            "/vsc_mock/:user_id",
-            get(mock_email_checker_handler::<AuthImpl>),
-        );
+            "/conversation/:conversation_id",
+            get(open_conversation).with_state(conversation_service.clone()),
+        )
+        .route(
+            "/conversation/:conversation_id/messages",
+            get(message_history).with_state(conversation_service.clone()),
+        )
+        .layer(*guard_htmx_auth.clone())
+        .with_state(Arc::clone(&kcloak_client));
+
+    let mut ws = Router::new()
+        .route("/chat/:user_id", get(chat_handler::<ChatImpl>))
+        .with_state(chat_service.clone())
+        .route("/vsc/:user_id", get(email_checker_handler::<AuthImpl>));
+
+    // the mocks send without a session, only for local development
+    if config.mock_routes {
+        ws = ws
+            .route(
+                "/mock/chat/:conversation_id",
+                get(mock_chat_handler_sender::<ChatImpl>).with_state(chat_service.clone()),
+            )
+            .route(
+                "/vsc_mock/:user_id",
+                get(mock_email_checker_handler::<AuthImpl>),
+            );
+    }
 
     let app = Router::new()
         .route("/error", get(error_page))
         .route("/login", get(login_page).post(signin::<AuthImpl>))
         .route("/signup", get(signup_page).post(signup::<AuthImpl>))
-        .route("/home", get(home_page))
-        .route("/", get(home_page))
+        .route(
+            "/forgot-password",
+            get(forgot_password_page).post(forgot_password::<AuthImpl>),
+        )
+        .route(
+            "/callback-reset-password",
+            get(callback_reset_password::<AuthImpl>),
+        )
+        .route(
+            "/reset-password-complete",
+            get(reset_password_complete_page),
+        )
+        .route("/logout", post(logout::<AuthImpl>))
+        .route("/auth/login", get(authorize::<AuthImpl>))
+        .route("/auth/callback", get(authorization_callback::<AuthImpl>))
+        .route("/home", get(home_page::<AuthImpl>))
+        .route("/", get(home_page::<AuthImpl>))
         .route(
             "/callback-verified-email",
             get(callback_verify_email::<AuthImpl>),
@@ -121,7 +264,11 @@ pub async fn run() {
         .nest_service("/assets", ServeDir::new("assets"))
         .with_state(auth);
 
-    let app = app.fallback(page_404);
+    // layered after the fallback so the 404 page gets a token as well
+    let app = app.fallback(page_404).layer(middleware::from_fn_with_state(
+        config.session_cookie.clone(),
+        csrf_middleware,
+    ));
 
     let host = "0.0.0.0";
     let port = 3000;
@@ -1,34 +1,78 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::net::SocketAddr;
 
 use axum::{
-    extract::{ws::Message, ConnectInfo, Path, State, WebSocketUpgrade},
+    extract::{ws::Message, ConnectInfo, Path, Query, State, WebSocketUpgrade},
+    http::StatusCode,
     response::IntoResponse,
 };
 
-use axum_extra::{headers, TypedHeader};
+use axum_extra::{extract::CookieJar, headers, TypedHeader};
 use futures::{sink::SinkExt, StreamExt};
-use rchaty_core::{chatchannel::master::MasterChannel, Auth, EmailVerifiedChannel};
-use rchaty_web::htmx::ChatIncomming;
+use rchaty_core::{
+    chatchannel::{
+        master::{ChannelEvent, Delivery},
+        model::{IncomingFrame, MessageStatus},
+    },
+    service::service_chat::Chat,
+    Auth, BaseError,
+};
+use rchaty_web::htmx::{
+    Alert, ChatIncomming, ChatOutgoing, ContactPreviewHtmx, ContactRequestNotificationHtmx,
+    MessageStatusHtmx, PresenceHtmx, TypingHtmx,
+};
+use tokio::sync::broadcast::{error::RecvError, Receiver};
 
-#[derive(Clone)]
-pub struct WsAppState {
-    pub email_verified_channel: Arc<dyn EmailVerifiedChannel + Send + Sync>,
-}
+use crate::{
+    htmx_handler::CONTACT_DATE_FORMAT,
+    middleware::{add_session_cookies, parse_auth, parse_ref_auth},
+    model::WsTicketParams,
+};
 
-pub async fn contact_list_handler<S>() -> impl IntoResponse {
-    todo!("implement contact list ws handler")
+/// Accepts the upgrade only when the token belongs to the user the socket is opened for.
+fn verify_sub(sub: Result<String, BaseError>, user_id: &str) -> Result<(), (StatusCode, String)> {
+    match sub {
+        Ok(sub) if sub == user_id => Ok(()),
+        Ok(sub) => {
+            tracing::warn!("user: {} tried to open a socket for {}", sub, user_id);
+            Err((
+                StatusCode::FORBIDDEN,
+                "user id does not match token".to_string(),
+            ))
+        }
+        Err(e) => Err((StatusCode::UNAUTHORIZED, e.messages)),
+    }
 }
 
 pub async fn email_checker_handler<S>(
     ws: WebSocketUpgrade,
+    jar: CookieJar,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Path(user_id): Path<String>,
+    Query(params): Query<WsTicketParams>,
     State(channel): State<S>,
 ) -> impl IntoResponse
 where
     S: Auth + Send + Sync + 'static,
 {
+    // right after signup there is no session yet, only the ticket from the signup response
+    let verified = match params.ticket {
+        Some(ticket) => channel
+            .verify_ws_ticket(&user_id, &ticket)
+            .await
+            .map_err(|e| (StatusCode::UNAUTHORIZED, e.messages)),
+        None => {
+            let sub = match parse_auth(&jar).await {
+                Some(token) => channel.authenticate(&token).await,
+                None => Err(BaseError::new(401, "missing auth token")),
+            };
+            verify_sub(sub, &user_id)
+        }
+    };
+    if let Err(resp) = verified {
+        return resp.into_response();
+    }
+
     let user_agent = if let Some(TypedHeader(user_agent)) = user_agent {
         user_agent.to_string()
     } else {
@@ -40,6 +84,7 @@ where
     );
     tracing::info!(msg);
     ws.on_upgrade(move |socket| email_checker_handler_socket::<S>(socket, user_id, channel))
+        .into_response()
 }
 
 async fn email_checker_handler_socket<T: Auth + Send + Sync>(
@@ -63,13 +108,14 @@ async fn email_checker_handler_socket<T: Auth + Send + Sync>(
 
 pub async fn chat_handler<S>(
     ws: WebSocketUpgrade,
+    jar: CookieJar,
     user_agent: Option<TypedHeader<headers::UserAgent>>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     Path(user_id): Path<String>,
     State(state): State<S>,
 ) -> impl IntoResponse
 where
-    S: MasterChannel,
+    S: Chat + Send + Sync + 'static,
 {
     tracing::info!(
         "user: {}, agent: {:?} at {} connected.",
@@ -78,30 +124,171 @@ where
         addr
     );
 
-    state.create_channel(user_id.as_str());
-    let tx = state.tx(user_id.as_str()).await;
-    let tx = match tx {
-        Some(tx) => tx,
-        None => {
-            let msg = format!("channel does not exist for user_id: {}", user_id);
-            tracing::error!(msg);
-            return "channel does not exist".into_response();
-        }
+    let sub = match parse_auth(&jar).await {
+        Some(token) => state.authenticate(&token).await,
+        None => Err(BaseError::new(401, "missing auth token")),
+    };
+    // a reconnect after the access token expired still carries the refresh token,
+    // renew the session as `home_page` does and hand the cookies back with the upgrade
+    let (sub, jar) = match sub {
+        Ok(sub) => (Ok(sub), None),
+        Err(e) => match parse_ref_auth(&jar).await {
+            Some(refresh_token) => match state.refresh(&refresh_token).await {
+                Ok(session) => (
+                    state.authenticate(&session.token).await,
+                    Some(add_session_cookies(
+                        jar,
+                        state.get_session_cookie(),
+                        &session,
+                    )),
+                ),
+                Err(e) => (Err(e), None),
+            },
+            None => (Err(e), None),
+        },
     };
-    ws.on_upgrade(|socket| async move {
-        let mut rx = tx.subscribe();
-        let (mut sender, mut _receiver) = socket.split();
+    if let Err(resp) = verify_sub(sub, &user_id) {
+        return resp.into_response();
+    }
 
-        let _send_task = tokio::spawn(async move {
-            loop {
-                let msg = rx.recv().await;
-                if !msg.is_ok() {
+    // the channel is released once this receiver is dropped with the socket
+    let rx = state.get_master_channel().subscribe(user_id.as_str());
+    let upgrade = ws.on_upgrade(move |socket| async move {
+        let presence = state.get_presence();
+        if let Err(e) = presence.connect(&user_id).await {
+            tracing::warn!("user: {} failed to publish presence: {}", user_id, e);
+        }
+        chat_handler_socket::<S>(socket, &user_id, rx, state).await;
+        if let Err(e) = presence.disconnect(&user_id).await {
+            tracing::warn!("user: {} failed to publish presence: {}", user_id, e);
+        }
+    });
+    (jar, upgrade).into_response()
+}
+
+async fn chat_handler_socket<S: Chat + Send + Sync>(
+    socket: axum::extract::ws::WebSocket,
+    user_id: &str,
+    mut rx: Receiver<Delivery>,
+    state: S,
+) {
+    let (mut sender, mut receiver) = socket.split();
+    loop {
+        tokio::select! {
+            msg = rx.recv() => {
+                let msg = match msg {
+                    Ok(msg) => msg,
+                    Err(RecvError::Lagged(skipped)) => {
+                        tracing::warn!("user: {} lagged, skipped {} messages", user_id, skipped);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                };
+                let event = msg.event.as_ref();
+                let frame = match event {
+                    ChannelEvent::Presence(presence) => PresenceHtmx::htmx(
+                        &presence.user_id,
+                        presence.online,
+                        presence.last_seen_at.as_deref().unwrap_or_default(),
+                    ),
+                    ChannelEvent::Typing(typing) if typing.user_id == user_id => continue,
+                    ChannelEvent::Typing(typing) => TypingHtmx::htmx(&typing.conversation_id, &typing.user_id, &typing.username, typing.typing),
+                    ChannelEvent::MessageStatus(message) => {
+                        MessageStatusHtmx::htmx(&message.id, &message.status.to_string())
+                    }
+                    ChannelEvent::ContactRequest(request) => ContactRequestNotificationHtmx::htmx(
+                        &format!("{} wants to add you as a contact", request.sender_name),
+                        false,
+                    ),
+                    ChannelEvent::ContactRequestAccepted(request) => ContactRequestNotificationHtmx::htmx(
+                        &format!("{} accepted your contact request", request.recipient_name),
+                        true,
+                    ),
+                    ChannelEvent::ContactRequestCancelled(request) => ContactRequestNotificationHtmx::htmx(
+                        &format!("{} cancelled their contact request", request.sender_name),
+                        false,
+                    ),
+                    ChannelEvent::ChatMessage(message) if message.author.id == user_id => ChatOutgoing::htmx(
+                        &message.id,
+                        &message.conversation_id,
+                        &message.content,
+                        &message.created_at,
+                        &message.status.to_string(),
+                    ),
+                    ChannelEvent::ChatMessage(message) => {
+                        // direct messages also refresh the author's entry in the contact list
+                        let preview = match state.contact_preview(user_id, message).await {
+                            Ok(preview) => preview,
+                            Err(e) => {
+                                tracing::warn!("user: {} failed to preview contact: {}", user_id, e);
+                                None
+                            }
+                        };
+                        let contact = preview.as_ref().map(|preview| ContactPreviewHtmx {
+                            user_id: &preview.friend_id,
+                            last_message: &preview.last_message,
+                            last_message_at: preview
+                                .last_message_at
+                                .format(CONTACT_DATE_FORMAT)
+                                .to_string(),
+                            unread_count: preview.unread_count,
+                        });
+                        ChatIncomming::htmx(
+                            &message.id,
+                            &message.conversation_id,
+                            &message.content,
+                            &message.created_at,
+                            !msg.muted,
+                            contact,
+                        )
+                    }
+                };
+                if sender.send(Message::Text(frame)).await.is_err() {
                     break;
                 }
-                let msg = msg.unwrap();
-                let msg = ChatIncomming::htmx(&msg.content(), &msg.created_at());
-                sender.send(Message::Text(msg)).await.unwrap();
+
+                // the message reached one of the recipient's sockets
+                if let ChannelEvent::ChatMessage(message) = event {
+                    if message.author.id == user_id || message.status != MessageStatus::Sent {
+                        continue;
+                    }
+                    let resp = state
+                        .update_message_status(user_id, &message.id, MessageStatus::Delivered)
+                        .await;
+                    if let Err(e) = resp {
+                        tracing::warn!("user: {} failed to mark message delivered: {}", user_id, e);
+                    }
+                }
+            }
+            frame = receiver.next() => {
+                let text = match frame {
+                    Some(Ok(Message::Text(text))) => text,
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(_)) => continue,
+                };
+                let resp = match serde_json::from_str::<IncomingFrame>(&text) {
+                    Ok(IncomingFrame::ChatMessage(incoming)) => state
+                        .send_incoming_message(user_id, incoming)
+                        .await
+                        .map(|_| ()),
+                    Ok(IncomingFrame::Read { message_id }) => {
+                        state
+                            .update_message_status(user_id, &message_id, MessageStatus::Read)
+                            .await
+                    }
+                    Ok(IncomingFrame::Typing { conversation_id }) => {
+                        state.send_typing(user_id, &conversation_id).await
+                    }
+                    Err(e) => Err(BaseError::new(400, &e.to_string())),
+                };
+                if let Err(e) = resp {
+                    tracing::warn!("user: {} failed to handle frame: {}", user_id, e);
+                    if sender.send(Message::Text(Alert::htmx(e.messages))).await.is_err() {
+                        break;
+                    }
+                }
             }
-        });
-    })
+        }
+    }
+    tracing::info!("user: {} disconnected.", user_id);
 }
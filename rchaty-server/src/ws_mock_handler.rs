@@ -1,5 +1,3 @@
-use std::sync::Arc;
-
 use axum::{
     body::Body,
     extract::{Path, State},
@@ -7,37 +5,24 @@ use axum::{
 };
 
 use rchaty_core::{
-    chatchannel::{
-        master::{ChannelDataImpl, MasterChannel},
-        model::{Author, ContentType, MessageData, MessageStatus},
-    },
+    chatchannel::model::{Author, ContentType, MessageData, MessageStatus},
+    service::service_chat::Chat,
     Auth, EmailVerifiedMessage,
 };
 use rchaty_web::htmx::VerifiedEmailSuccess;
 
 pub async fn mock_chat_handler_sender<S>(
-    Path(user_id): Path<String>,
+    Path(conversation_id): Path<String>,
     State(state): State<S>,
 ) -> Response<Body>
 where
-    S: MasterChannel,
+    S: Chat,
 {
-    let tx = state.tx(user_id.as_str()).await;
-    let tx = match tx {
-        Some(tx) => tx,
-        None => {
-            let msg = format!("channel does not exist for user_id: {}", user_id);
-            tracing::error!(msg);
-            return "channel does not exist".into_response();
-        }
-    };
-
     let data = {
         let id = "26e03da6-38f0-4951-b00b-16ef0ab0cd8b".to_string();
-        let conversation_id = "world".to_string();
         let author = Author::new(
             "9925ce5d-6174-4fd7-b978-018976280eb1".to_owned(),
-            user_id.to_owned(),
+            "mock".to_owned(),
             "email_test@example.com".to_owned(),
             "https://gravatar.com/avatar/9925ce5d61744fd7b978018976280eb1".to_owned(),
         );
@@ -47,7 +32,7 @@ where
         let created_at = "2022-01-01 00:00:00".to_string();
         MessageData::new(
             id,
-            conversation_id,
+            conversation_id.to_owned(),
             author,
             content,
             content_type,
@@ -56,16 +41,16 @@ where
         )
     };
 
-    let msg = Arc::new(ChannelDataImpl::new_chat_msg(data));
-    let resp = tx.send(msg);
+    let resp = state.send_message(data).await;
     match resp {
         Ok(_) => {
-            let msg = format!("sended data for user_id: {}", user_id);
+            let msg = format!("sended data for conversation_id: {}", conversation_id);
             tracing::info!(msg)
         }
         Err(e) => {
             let msg = format!("send data failed: {:}", e);
-            tracing::error!(msg)
+            tracing::error!(msg);
+            return e.messages.into_response();
         }
     }
     "ok".into_response()
@@ -4,8 +4,11 @@ use askama::Template;
 #[template(path = "pages/error.html")]
 pub struct ErrorTemplate<'a> {
     pub error: &'a str,
+    pub csrf_token: String,
 }
 
 #[derive(Template)]
 #[template(path = "pages/404.html")]
-pub struct Page404Template {}
+pub struct Page404Template {
+    pub csrf_token: String,
+}
@@ -1,5 +1,10 @@
 use askama::Template;
 
+/// Dates are stored down to the microsecond but shown down to the second.
+fn display_date(date: &str) -> &str {
+    date.split('.').next().unwrap_or(date)
+}
+
 #[derive(Template)]
 #[template(path = "login_clicked.html")]
 pub struct LoginClicked {}
@@ -32,11 +37,25 @@ impl<'a> RedirectHtmx<'a> {
 #[template(path = "htmx/verified_email_checker.html")]
 pub struct VerifiedEmailChecker {
     pub user_id: String,
+    pub ticket: String,
 }
 
 impl VerifiedEmailChecker {
-    pub fn htmx(user_id: String) -> String {
-        let template = VerifiedEmailChecker { user_id };
+    pub fn htmx(user_id: String, ticket: String) -> String {
+        let template = VerifiedEmailChecker { user_id, ticket };
+        template.render().unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "htmx/reset_password_sent.html")]
+pub struct ResetPasswordSent<'a> {
+    pub email: &'a str,
+}
+
+impl<'a> ResetPasswordSent<'a> {
+    pub fn htmx(email: &'a str) -> String {
+        let template = ResetPasswordSent { email };
         template.render().unwrap()
     }
 }
@@ -53,44 +72,151 @@ impl VerifiedEmailSuccess {
 }
 
 #[derive(Template)]
-#[template(path = "htmx/store_auth_token.html")]
-pub struct StoreAuthToken<'a> {
-    pub need_redirect: bool,
+#[template(path = "htmx/clear_auth_token.html")]
+pub struct ClearAuthToken<'a> {
     pub redirect_url: &'a str,
-    pub token: String,
-    pub refresh_token: String,
-    pub expires_in: i64,
 }
 
-impl<'a> StoreAuthToken<'a> {
+impl<'a> ClearAuthToken<'a> {
+    pub fn htmx(redirect_url: &'a str) -> String {
+        ClearAuthToken { redirect_url }.render().unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "htmx/chat_incoming.html")]
+pub struct ChatIncomming<'a> {
+    pub id: &'a str,
+    pub conversation_id: &'a str,
+    pub content: &'a str,
+    pub date: &'a str,
+    /// Off for muted authors, the message shows up without the notification badge.
+    pub notify: bool,
+    /// The author's contact list entry, for direct messages only.
+    pub contact: Option<ContactPreviewHtmx<'a>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ContactPreviewHtmx<'a> {
+    pub user_id: &'a str,
+    pub last_message: &'a str,
+    pub last_message_at: String,
+    pub unread_count: i64,
+}
+
+impl<'a> ChatIncomming<'a> {
     pub fn htmx(
-        need_redirect: bool,
-        redirect_url: &str,
-        token: String,
-        refresh_token: String,
-        expires_in: i64,
+        id: &'a str,
+        conversation_id: &'a str,
+        content: &'a str,
+        date: &'a str,
+        notify: bool,
+        contact: Option<ContactPreviewHtmx<'a>>,
     ) -> String {
-        let template = StoreAuthToken {
-            need_redirect,
-            redirect_url,
-            token,
-            refresh_token,
-            expires_in,
+        let template = ChatIncomming {
+            id,
+            conversation_id,
+            content,
+            date: display_date(date),
+            notify,
+            contact,
         };
         template.render().unwrap()
     }
 }
 
 #[derive(Template)]
-#[template(path = "htmx/chat_incoming.html")]
-pub struct ChatIncomming<'a> {
+#[template(path = "htmx/chat_outgoing.html")]
+pub struct ChatOutgoing<'a> {
+    pub id: &'a str,
+    pub conversation_id: &'a str,
     pub content: &'a str,
     pub date: &'a str,
+    pub status: &'a str,
 }
 
-impl<'a> ChatIncomming<'a> {
-    pub fn htmx(content: &'a str, date: &'a str) -> String {
-        let template = ChatIncomming { content, date };
+impl<'a> ChatOutgoing<'a> {
+    pub fn htmx(
+        id: &'a str,
+        conversation_id: &'a str,
+        content: &'a str,
+        date: &'a str,
+        status: &'a str,
+    ) -> String {
+        let template = ChatOutgoing {
+            id,
+            conversation_id,
+            content,
+            date: display_date(date),
+            status,
+        };
+        template.render().unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "htmx/message_status.html")]
+pub struct MessageStatusHtmx<'a> {
+    pub message_id: &'a str,
+    pub status: &'a str,
+}
+
+impl<'a> MessageStatusHtmx<'a> {
+    pub fn htmx(message_id: &'a str, status: &'a str) -> String {
+        let template = MessageStatusHtmx { message_id, status };
+        template.render().unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "htmx/chat_history.html")]
+pub struct ChatHistoryHtmx<'a> {
+    pub conversation_id: &'a str,
+    pub messages: &'a Vec<ChatMessageHtmx<'a>>,
+    pub next_cursor: Option<&'a str>,
+    pub first_page: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct ChatMessageHtmx<'a> {
+    pub id: &'a str,
+    pub content: &'a str,
+    pub date: &'a str,
+    pub status: String,
+    pub outgoing: bool,
+}
+
+impl<'a> ChatMessageHtmx<'a> {
+    pub fn new(
+        id: &'a str,
+        content: &'a str,
+        date: &'a str,
+        status: String,
+        outgoing: bool,
+    ) -> Self {
+        ChatMessageHtmx {
+            id,
+            content,
+            date: display_date(date),
+            status,
+            outgoing,
+        }
+    }
+}
+
+impl<'a> ChatHistoryHtmx<'a> {
+    pub fn htmx(
+        conversation_id: &'a str,
+        messages: &'a Vec<ChatMessageHtmx<'a>>,
+        next_cursor: Option<&'a str>,
+        first_page: bool,
+    ) -> String {
+        let template = ChatHistoryHtmx {
+            conversation_id,
+            messages,
+            next_cursor,
+            first_page,
+        };
         template.render().unwrap()
     }
 }
@@ -105,11 +231,229 @@ pub struct ContactListHtmx<'a> {
 pub struct ContactItemHtmx<'a> {
     pub user_id: &'a str,
     pub name: &'a str,
+    pub online: bool,
+    pub last_seen: String,
+    /// "block", "mute" or empty.
+    pub restriction: String,
+    /// Empty when the conversation has no message yet.
+    pub last_message: &'a str,
+    pub last_message_at: String,
+    pub unread_count: i64,
 }
 
 impl<'a> ContactItemHtmx<'a> {
-    pub fn new(user_id: &'a str, name: &'a str) -> Self {
-        ContactItemHtmx { user_id, name }
+    pub fn new(
+        user_id: &'a str,
+        name: &'a str,
+        online: bool,
+        last_seen: String,
+        restriction: String,
+    ) -> Self {
+        ContactItemHtmx {
+            user_id,
+            name,
+            online,
+            last_seen: display_date(&last_seen).to_string(),
+            restriction,
+            last_message: "",
+            last_message_at: String::new(),
+            unread_count: 0,
+        }
+    }
+
+    pub fn with_last_message(
+        mut self,
+        last_message: &'a str,
+        last_message_at: String,
+        unread_count: i64,
+    ) -> Self {
+        self.last_message = last_message;
+        self.last_message_at = last_message_at;
+        self.unread_count = unread_count;
+        self
+    }
+}
+
+#[derive(Template)]
+#[template(path = "htmx/contact_rename.html")]
+pub struct ContactRenameHtmx<'a> {
+    pub user_id: &'a str,
+    pub name: &'a str,
+    pub max_length: usize,
+}
+
+impl<'a> ContactRenameHtmx<'a> {
+    pub fn htmx(user_id: &'a str, name: &'a str, max_length: usize) -> String {
+        let template = ContactRenameHtmx {
+            user_id,
+            name,
+            max_length,
+        };
+        template.render().unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "htmx/restricted_users.html")]
+pub struct RestrictedUsersHtmx<'a> {
+    pub users: &'a Vec<RestrictedUserHtmx<'a>>,
+    /// Also reloads the contact list, after a restriction changed.
+    pub refresh_contacts: bool,
+}
+
+impl<'a> RestrictedUsersHtmx<'a> {
+    pub fn htmx(users: &'a Vec<RestrictedUserHtmx<'a>>, refresh_contacts: bool) -> String {
+        let template = RestrictedUsersHtmx {
+            users,
+            refresh_contacts,
+        };
+        template.render().unwrap()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RestrictedUserHtmx<'a> {
+    pub user_id: &'a str,
+    pub name: &'a str,
+    /// "block" or "mute".
+    pub restriction: String,
+}
+
+impl<'a> RestrictedUserHtmx<'a> {
+    pub fn new(user_id: &'a str, name: &'a str, restriction: String) -> Self {
+        RestrictedUserHtmx {
+            user_id,
+            name,
+            restriction,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "htmx/contact_requests.html")]
+pub struct ContactRequestsHtmx<'a> {
+    pub incoming: &'a Vec<ContactRequestItemHtmx<'a>>,
+    pub outgoing: &'a Vec<ContactRequestItemHtmx<'a>>,
+    /// Also reloads the contact list, after a request was accepted.
+    pub refresh_contacts: bool,
+}
+
+impl<'a> ContactRequestsHtmx<'a> {
+    pub fn htmx(
+        incoming: &'a Vec<ContactRequestItemHtmx<'a>>,
+        outgoing: &'a Vec<ContactRequestItemHtmx<'a>>,
+        refresh_contacts: bool,
+    ) -> String {
+        let template = ContactRequestsHtmx {
+            incoming,
+            outgoing,
+            refresh_contacts,
+        };
+        template.render().unwrap()
+    }
+}
+
+/// A pending request, `user_id` and `name` are the other party's.
+#[derive(Debug, Clone)]
+pub struct ContactRequestItemHtmx<'a> {
+    pub id: &'a str,
+    pub user_id: &'a str,
+    pub name: &'a str,
+}
+
+impl<'a> ContactRequestItemHtmx<'a> {
+    pub fn new(id: &'a str, user_id: &'a str, name: &'a str) -> Self {
+        ContactRequestItemHtmx { id, user_id, name }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "htmx/contact_request_notification.html")]
+pub struct ContactRequestNotificationHtmx<'a> {
+    pub message: &'a str,
+    pub refresh_contacts: bool,
+}
+
+impl<'a> ContactRequestNotificationHtmx<'a> {
+    pub fn htmx(message: &'a str, refresh_contacts: bool) -> String {
+        let template = ContactRequestNotificationHtmx {
+            message,
+            refresh_contacts,
+        };
+        template.render().unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "htmx/user_search.html")]
+pub struct UserSearchHtmx<'a> {
+    pub users: &'a Vec<UserItemHtmx<'a>>,
+    /// Whether the query was long enough to run, an empty list otherwise says nothing.
+    pub searched: bool,
+}
+
+impl<'a> UserSearchHtmx<'a> {
+    pub fn htmx(users: &'a Vec<UserItemHtmx<'a>>, searched: bool) -> String {
+        let template = UserSearchHtmx { users, searched };
+        template.render().unwrap()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct UserItemHtmx<'a> {
+    pub user_id: &'a str,
+    pub username: &'a str,
+    pub name: &'a str,
+}
+
+impl<'a> UserItemHtmx<'a> {
+    pub fn new(user_id: &'a str, username: &'a str, name: &'a str) -> Self {
+        UserItemHtmx {
+            user_id,
+            username,
+            name,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "htmx/presence.html")]
+pub struct PresenceHtmx<'a> {
+    pub user_id: &'a str,
+    pub online: bool,
+    pub last_seen: &'a str,
+}
+
+impl<'a> PresenceHtmx<'a> {
+    pub fn htmx(user_id: &'a str, online: bool, last_seen: &'a str) -> String {
+        let template = PresenceHtmx {
+            user_id,
+            online,
+            last_seen: display_date(last_seen),
+        };
+        template.render().unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "htmx/typing.html")]
+pub struct TypingHtmx<'a> {
+    pub conversation_id: &'a str,
+    pub user_id: &'a str,
+    pub name: &'a str,
+    pub typing: bool,
+}
+
+impl<'a> TypingHtmx<'a> {
+    /// Only shows up while `conversation_id` is the open conversation, one line per member typing.
+    pub fn htmx(conversation_id: &'a str, user_id: &'a str, name: &'a str, typing: bool) -> String {
+        let template = TypingHtmx {
+            conversation_id,
+            user_id,
+            name,
+            typing,
+        };
+        template.render().unwrap()
     }
 }
 
@@ -119,3 +463,48 @@ impl<'a> ContactListHtmx<'a> {
         template.render().unwrap()
     }
 }
+
+#[derive(Template)]
+#[template(path = "htmx/conversation_selected.html")]
+pub struct ConversationSelectedHtmx<'a> {
+    pub conversation_id: &'a str,
+    pub name: &'a str,
+}
+
+impl<'a> ConversationSelectedHtmx<'a> {
+    pub fn htmx(conversation_id: &'a str, name: &'a str) -> String {
+        let template = ConversationSelectedHtmx {
+            conversation_id,
+            name,
+        };
+        template.render().unwrap()
+    }
+}
+
+#[derive(Template)]
+#[template(path = "htmx/conversation_list.html")]
+pub struct ConversationListHtmx<'a> {
+    pub conversations: &'a Vec<ConversationItemHtmx<'a>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct ConversationItemHtmx<'a> {
+    pub conversation_id: &'a str,
+    pub name: &'a str,
+}
+
+impl<'a> ConversationItemHtmx<'a> {
+    pub fn new(conversation_id: &'a str, name: &'a str) -> Self {
+        ConversationItemHtmx {
+            conversation_id,
+            name,
+        }
+    }
+}
+
+impl<'a> ConversationListHtmx<'a> {
+    pub fn htmx(conversations: &'a Vec<ConversationItemHtmx<'a>>) -> String {
+        let template = ConversationListHtmx { conversations };
+        template.render().unwrap()
+    }
+}
@@ -2,12 +2,31 @@ use askama::Template;
 
 #[derive(Template)]
 #[template(path = "pages/login.html")]
-pub struct LoginTemplate {}
+pub struct LoginTemplate {
+    pub csrf_token: String,
+}
 
 #[derive(Template)]
 #[template(path = "pages/signup.html")]
-pub struct SignupTemplate {}
+pub struct SignupTemplate {
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "pages/forgot_password.html")]
+pub struct ForgotPasswordTemplate {
+    pub csrf_token: String,
+}
+
+#[derive(Template)]
+#[template(path = "pages/reset_password_complete.html")]
+pub struct ResetPasswordCompleteTemplate {
+    pub csrf_token: String,
+}
 
 #[derive(Template)]
 #[template(path = "pages/home.html")]
-pub struct HomeTemplate {}
+pub struct HomeTemplate {
+    pub user_id: String,
+    pub csrf_token: String,
+}